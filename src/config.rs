@@ -1,16 +1,26 @@
-use crate::tasks::Task;
+use crate::disk;
 use crate::tasks::TASKS;
 use crate::utils::*;
+use crate::validate::{validate, validate_stages};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::fmt;
 use std::fs::File;
 use std::io::Read;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 #[derive(Serialize, Deserialize)]
 pub struct Config {
     pub installer: Installer,
     pub user: User,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub swap: Option<Swap>,
+    #[serde(default)]
+    pub system: System,
+    #[serde(default, skip_serializing_if = "Hardware::is_unset")]
+    pub hardware: Hardware,
+    #[serde(default, skip_serializing_if = "Bootloader::is_default")]
+    pub bootloader: Bootloader,
     pub stages: Stages,
     #[serde(default, skip)]
     pub path: PathBuf,
@@ -22,47 +32,747 @@ pub struct User {
     pub full_name: String,
     pub email: String,
     pub hostname: String,
+    /// Git url of a bare dotfiles repository checked out into the home directory.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dotfiles: Option<String>,
 }
+
+impl User {
+    pub fn home(&self) -> PathBuf {
+        Path::new("/home").join(&self.name)
+    }
+}
+/// Settings of the installed system.
+#[derive(Serialize, Deserialize)]
+pub struct System {
+    /// Name in the tz database, e.g. `Europe/Berlin`.
+    #[serde(default = "default_timezone")]
+    pub timezone: String,
+    /// Locales to generate, as lines of `/etc/locale.gen`.
+    #[serde(default = "default_locales")]
+    pub locales: Vec<String>,
+    /// `LANG`, the first of `locales` if missing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lang: Option<String>,
+    /// Console keymap.
+    #[serde(default = "default_keymap")]
+    pub keymap: String,
+    /// Console font, the kernel's default if missing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub font: Option<String>,
+}
+
+fn default_timezone() -> String {
+    "UTC".to_string()
+}
+
+fn default_locales() -> Vec<String> {
+    vec!["en_US.UTF-8 UTF-8".to_string()]
+}
+
+fn default_keymap() -> String {
+    "us".to_string()
+}
+
+impl Default for System {
+    fn default() -> System {
+        System {
+            timezone: default_timezone(),
+            locales: default_locales(),
+            lang: None,
+            keymap: default_keymap(),
+            font: None,
+        }
+    }
+}
+
+impl System {
+    pub fn lang(&self) -> &str {
+        match &self.lang {
+            Some(lang) => lang,
+            None => self
+                .locales
+                .first()
+                .and_then(|locale| locale.split_whitespace().next())
+                .unwrap_or("C"),
+        }
+    }
+}
+
+/// Hardware of the machine, as written by `detect`.
+#[derive(Serialize, Deserialize, Default)]
+pub struct Hardware {
+    /// Decides the microcode package, looked up in `/proc/cpuinfo` if missing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu: Option<CpuVendor>,
+    #[serde(default, skip_serializing_if = "Microcode::is_auto")]
+    pub microcode: Microcode,
+    #[serde(default, skip_serializing_if = "Gpu::is_auto")]
+    pub gpu: Gpu,
+}
+
+impl Hardware {
+    fn is_unset(&self) -> bool {
+        self.cpu.is_none() && self.microcode.is_auto() && self.gpu.is_auto()
+    }
+}
+
+/// Graphics driver installed by the `vga` task.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Gpu {
+    /// the one for the GPU `lspci` lists
+    #[default]
+    Auto,
+    Amd,
+    Intel,
+    Nvidia,
+    /// generic driver for anything else
+    Vesa,
+}
+
+impl Gpu {
+    fn is_auto(&self) -> bool {
+        *self == Gpu::Auto
+    }
+}
+
+/// Microcode updates loaded before the initramfs.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Microcode {
+    /// the one for the CPU of this machine
+    #[default]
+    Auto,
+    Intel,
+    Amd,
+    /// both, for installs that move between machines
+    Both,
+    None,
+}
+
+impl Microcode {
+    fn is_auto(&self) -> bool {
+        *self == Microcode::Auto
+    }
+}
+
+/// How the installed system boots.
+#[derive(Serialize, Deserialize)]
+pub struct Bootloader {
+    #[serde(default)]
+    pub kind: BootloaderKind,
+    #[serde(default)]
+    pub firmware: Firmware,
+    /// Boot unified kernel images built by mkinitcpio instead of a separate
+    /// kernel and initramfs, systemd-boot only.
+    #[serde(default)]
+    pub uki: bool,
+    /// Sign the boot chain with keys generated by sbctl, needs `uki`.
+    #[serde(default)]
+    pub secure_boot: bool,
+    /// Name of the kernel booted by default, the first of `kernels` if missing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_kernel: Option<String>,
+    /// Seconds the menu is shown for.
+    #[serde(default = "default_timeout")]
+    pub timeout: u32,
+    /// systemd-boot `console-mode`: keep, auto, max or a mode number.
+    #[serde(default = "default_console_mode")]
+    pub console_mode: String,
+    /// Allow editing the kernel command line from the systemd-boot menu.
+    #[serde(default)]
+    pub editor: bool,
+    /// Kernel options for every kernel, like `quiet` or `nvidia-drm.modeset=1`,
+    /// added to those the installation needs.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub kernel_params: Vec<String>,
+    /// Kernels to install, each gets a boot entry and a fallback one.
+    // Last because it's written as `[[bootloader.kernels]]` tables, and toml
+    // can't have plain values after the tables of a section.
+    #[serde(default = "default_kernels")]
+    pub kernels: Vec<Kernel>,
+}
+
+/// Kernel packages of the official repositories.
+pub const KERNELS: [&str; 4] = ["linux", "linux-lts", "linux-zen", "linux-hardened"];
+
+/// A kernel package, one of `KERNELS`.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct Kernel {
+    pub name: String,
+    /// Kernel options only this kernel boots with.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub params: Vec<String>,
+}
+
+fn default_timeout() -> u32 {
+    3
+}
+
+fn default_console_mode() -> String {
+    "keep".to_string()
+}
+
+fn default_kernels() -> Vec<Kernel> {
+    vec![Kernel {
+        name: "linux".to_string(),
+        params: Vec::new(),
+    }]
+}
+
+impl Default for Bootloader {
+    fn default() -> Bootloader {
+        Bootloader {
+            kind: BootloaderKind::default(),
+            firmware: Firmware::default(),
+            uki: false,
+            secure_boot: false,
+            kernels: default_kernels(),
+            default_kernel: None,
+            timeout: default_timeout(),
+            console_mode: default_console_mode(),
+            editor: false,
+            kernel_params: Vec::new(),
+        }
+    }
+}
+
+impl Bootloader {
+    fn is_default(&self) -> bool {
+        self.kind == BootloaderKind::SystemdBoot
+            && self.firmware == Firmware::Auto
+            && !self.uki
+            && !self.secure_boot
+            && self.kernels == default_kernels()
+            && self.default_kernel.is_none()
+            && self.timeout == default_timeout()
+            && self.console_mode == default_console_mode()
+            && !self.editor
+            && self.kernel_params.is_empty()
+    }
+
+    pub fn default_kernel(&self) -> &str {
+        match &self.default_kernel {
+            Some(name) => name,
+            None => self.kernels.first().map_or("linux", |k| k.name.as_str()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum BootloaderKind {
+    /// UEFI only
+    #[default]
+    SystemdBoot,
+    /// UEFI or BIOS
+    Grub,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Firmware {
+    /// whatever the installer was booted with
+    #[default]
+    Auto,
+    Uefi,
+    Bios,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum CpuVendor {
+    Intel,
+    Amd,
+}
+
+/// Where the installed system swaps to.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "mode", rename_all = "lowercase")]
+pub enum Swap {
+    Partition {
+        #[serde(with = "device_path_from_name")]
+        device: PathBuf,
+        /// Resume from it after hibernation.
+        #[serde(default)]
+        hibernate: bool,
+    },
+    File {
+        /// Size as understood by `fallocate`, e.g. `8G`.
+        size: String,
+        /// `/swapfile`, or `/swap/swapfile` in its own subvolume on btrfs, if missing.
+        #[serde(default, skip_serializing_if = "path_is_empty")]
+        path: PathBuf,
+        #[serde(default)]
+        hibernate: bool,
+    },
+    Zram {
+        /// zram-generator expression, e.g. `min(ram / 2, 4096)`.
+        #[serde(default = "default_zram_size")]
+        size: String,
+    },
+}
+
+fn path_is_empty(path: &Path) -> bool {
+    path.as_os_str().is_empty()
+}
+
+fn default_zram_size() -> String {
+    "ram / 2".to_string()
+}
+
+impl Swap {
+    pub fn hibernate(&self) -> bool {
+        match self {
+            Swap::Partition { hibernate, .. } | Swap::File { hibernate, .. } => *hibernate,
+            Swap::Zram { .. } => false,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct Installer {
-    #[serde(with = "existing_device_path_from_name")]
+    #[serde(with = "device_path_from_name")]
     pub install_disk: PathBuf,
-    #[serde(with = "existing_device_path_from_name")]
+    /// Taken from the partition mounted at `/` when `partitions` are given.
+    #[serde(
+        default,
+        with = "device_path_from_name",
+        skip_serializing_if = "path_is_empty"
+    )]
     pub system_disk: PathBuf,
-    #[serde(with = "existing_device_path_from_name")]
+    /// Taken from the partition mounted at `/boot` when `partitions` are given.
+    #[serde(
+        default,
+        with = "device_path_from_name",
+        skip_serializing_if = "path_is_empty"
+    )]
     pub boot_disk: PathBuf,
+    /// Refuse to touch `install_disk` unless `lsblk` reports this model.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expected_model: Option<String>,
+    /// Refuse to touch `install_disk` unless it has about this size, e.g.
+    /// `512G` (binary units) or `500GB` (decimal units).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expected_size: Option<String>,
+    /// Where session logs go, `logs` next to the config file by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log_dir: Option<PathBuf>,
+    #[serde(default)]
+    pub partition_table: PartitionTable,
+    /// Filesystem of `system_disk`, ext4 if missing; a partition mounted
+    /// at `/` with a `filesystem` of its own takes precedence.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system_filesystem: Option<Filesystem>,
+    /// Layout `prepare` creates on `install_disk`, in order.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub partitions: Vec<Partition>,
+    /// Puts the system partition into a LUKS container.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<Encryption>,
+    /// Logical volumes for the system, on `system_disk` unless other
+    /// physical volumes are given.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lvm: Option<Lvm>,
+    /// Subvolume layout used when the system filesystem is btrfs.
+    #[serde(default, skip_serializing_if = "Btrfs::is_default")]
+    pub btrfs: Btrfs,
+}
+
+/// Volume group on top of the system disk; when `partitions` are declared,
+/// the partition mounted at `/` becomes its physical volume.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Lvm {
+    pub volume_group: String,
+    /// Used instead of the system disk if given.
+    #[serde(default, with = "device_paths_from_names")]
+    pub physical_volumes: Vec<PathBuf>,
+    pub volumes: Vec<LogicalVolume>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct LogicalVolume {
+    pub name: String,
+    /// Either a size like `50G` or extents like `100%FREE`.
+    pub size: String,
+    /// Left unformatted if missing, except for `/` (ext4).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filesystem: Option<Filesystem>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mount_point: Option<String>,
+}
+
+impl Lvm {
+    /// Logical volume mounted at `/`.
+    pub fn root(&self) -> Option<&LogicalVolume> {
+        self.volumes
+            .iter()
+            .find(|v| v.mount_point.as_deref() == Some("/"))
+    }
+
+    pub fn volume_path(&self, volume: &LogicalVolume) -> PathBuf {
+        ["/dev", &self.volume_group, &volume.name].iter().collect()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct Btrfs {
+    pub subvolumes: Vec<Subvolume>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct Subvolume {
+    pub name: String,
+    pub mount_point: String,
+    /// Mount options besides `subvol=`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub options: Option<String>,
+}
+
+impl Default for Btrfs {
+    fn default() -> Btrfs {
+        let subvolumes = [
+            ("@", "/"),
+            ("@home", "/home"),
+            ("@log", "/var/log"),
+            ("@pkg", "/var/cache/pacman/pkg"),
+            ("@snapshots", "/.snapshots"),
+            // btrfs can't snapshot a subvolume with an active swap file
+            ("@swap", "/swap"),
+        ];
+        Btrfs {
+            subvolumes: subvolumes
+                .iter()
+                .map(|(name, mount_point)| Subvolume {
+                    name: name.to_string(),
+                    mount_point: mount_point.to_string(),
+                    options: Some("noatime,compress=zstd".to_string()),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Btrfs {
+    fn is_default(&self) -> bool {
+        *self == Btrfs::default()
+    }
+
+    /// Subvolume mounted at `/`.
+    pub fn root(&self) -> Option<&Subvolume> {
+        self.subvolumes.iter().find(|s| s.mount_point == "/")
+    }
+
+    /// Subvolume that holds `path` of the installed system.
+    pub fn holding(&self, path: &Path) -> Option<&Subvolume> {
+        self.subvolumes
+            .iter()
+            .filter(|s| path.starts_with(&s.mount_point))
+            .max_by_key(|s| s.mount_point.len())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Encryption {
+    /// Name of the opened container under `/dev/mapper`.
+    #[serde(default = "default_mapper_name")]
+    pub name: String,
+    #[serde(default)]
+    pub initramfs: Initramfs,
+}
+
+fn default_mapper_name() -> String {
+    "cryptroot".to_string()
+}
+
+/// Kind of initramfs, decides which hook unlocks the container.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Initramfs {
+    /// `encrypt` hook, `cryptdevice=` kernel option
+    #[default]
+    Busybox,
+    /// `sd-encrypt` hook, `rd.luks.name=` kernel option
+    Systemd,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PartitionTable {
+    #[default]
+    Gpt,
+    Dos,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Filesystem {
+    Fat32,
+    Ext4,
+    Btrfs,
+    Xfs,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Partition {
+    /// Size as understood by sfdisk, e.g. `512MiB`; the rest of the disk if missing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<String>,
+    /// Partition type GUID (or MBR type code), Linux filesystem if missing
+    /// (the EFI system partition for `/boot` on gpt).
+    #[serde(default, rename = "type", skip_serializing_if = "Option::is_none")]
+    pub type_guid: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// Left unformatted if missing, except for `/` (ext4) and `/boot` (fat32).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filesystem: Option<Filesystem>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mount_point: Option<String>,
+}
+
+impl Installer {
+    /// Device node of the `n`th (starting with 1) partition of `install_disk`.
+    pub fn partition_path(&self, n: usize) -> PathBuf {
+        let disk = self.install_disk.to_string_lossy();
+        if disk.ends_with(|c: char| c.is_ascii_digit()) {
+            format!("{}p{}", disk, n).into()
+        } else {
+            format!("{}{}", disk, n).into()
+        }
+    }
+
+    /// Declared partitions with their device nodes.
+    pub fn partition_devices(&self) -> Vec<(PathBuf, &Partition)> {
+        self.partitions
+            .iter()
+            .enumerate()
+            .map(|(i, p)| (self.partition_path(i + 1), p))
+            .collect()
+    }
+
+    /// `system_disk` as the rest of the system sees it, the opened
+    /// container when encrypted.
+    pub fn system_device(&self) -> PathBuf {
+        match &self.encryption {
+            Some(encryption) => Path::new("/dev/mapper").join(&encryption.name),
+            None => self.system_disk.clone(),
+        }
+    }
+
+    /// Device holding the root filesystem.
+    pub fn root_device(&self) -> PathBuf {
+        match &self.lvm {
+            Some(lvm) => match lvm.root() {
+                Some(root) => lvm.volume_path(root),
+                None => PathBuf::new(),
+            },
+            None => self.system_device(),
+        }
+    }
+
+    /// Whether `system_disk` is part of the layout, it isn't when LVM is
+    /// given its own physical volumes.
+    pub fn uses_system_disk(&self) -> bool {
+        self.encryption.is_some()
+            || self
+                .lvm
+                .as_ref()
+                .is_none_or(|lvm| lvm.physical_volumes.is_empty())
+    }
+
+    /// Physical volumes for LVM, the system disk if none are given.
+    pub fn physical_volumes(&self) -> Vec<PathBuf> {
+        match &self.lvm {
+            Some(lvm) if !lvm.physical_volumes.is_empty() => lvm.physical_volumes.clone(),
+            _ => vec![self.system_device()],
+        }
+    }
+
+    pub fn system_filesystem(&self) -> Filesystem {
+        let declared = match &self.lvm {
+            Some(lvm) => lvm.root().and_then(|v| v.filesystem),
+            None => self
+                .partitions
+                .iter()
+                .find(|p| p.mount_point.as_deref() == Some("/"))
+                .and_then(|p| p.filesystem),
+        };
+        declared
+            .or(self.system_filesystem)
+            .unwrap_or(Filesystem::Ext4)
+    }
+
+    /// Where a swap file goes when the config doesn't say.
+    pub fn default_swapfile(&self) -> PathBuf {
+        let swap_subvolume = self.btrfs.holding(Path::new("/swap/swapfile"));
+        if self.system_filesystem() == Filesystem::Btrfs && swap_subvolume != self.btrfs.root() {
+            "/swap/swapfile".into()
+        } else {
+            "/swapfile".into()
+        }
+    }
+
+    /// Replaces device specs by the device nodes they currently point to.
+    fn resolve_devices(&mut self) {
+        for device in [
+            &mut self.install_disk,
+            &mut self.system_disk,
+            &mut self.boot_disk,
+        ] {
+            if !device.as_os_str().is_empty() {
+                *device = disk::resolve(device);
+            }
+        }
+        if let Some(lvm) = &mut self.lvm {
+            for device in &mut lvm.physical_volumes {
+                *device = disk::resolve(device);
+            }
+        }
+    }
+
+    fn resolve_partitions(&mut self) {
+        for i in 0..self.partitions.len() {
+            let device = self.partition_path(i + 1);
+            match self.partitions[i].mount_point.as_deref() {
+                Some("/") => self.system_disk = device,
+                Some("/boot") => self.boot_disk = device,
+                _ => {}
+            }
+        }
+    }
 }
 
-pub type StagesMap = HashMap<String, Vec<Task>>;
+pub type StagesMap = HashMap<String, Vec<String>>;
 
 #[derive(Serialize, Deserialize)]
 pub struct Stages {
     pub first_stage: String,
-    #[serde(with = "stages_map")]
-    pub map: HashMap<String, Vec<Task>>,
+    /// Reorder tasks inside a stage to satisfy their dependencies
+    /// instead of rejecting the config.
+    #[serde(default)]
+    pub auto_order: bool,
+    pub map: StagesMap,
+}
+
+impl Stages {
+    /// Stages in the order the installation goes through them, starting
+    /// with `first_stage` and following the tasks that hand off to the next one.
+    pub fn chain(&self) -> Vec<&str> {
+        let mut chain: Vec<&str> = Vec::new();
+        let mut next = Some(self.first_stage.as_str());
+        while let Some(stage) = next.take() {
+            let tasks = match self.map.get(stage) {
+                Some(tasks) if !chain.contains(&stage) => tasks,
+                _ => break,
+            };
+            chain.push(stage);
+            next = tasks
+                .iter()
+                .filter_map(|task| TASKS.get(task).and_then(|t| t.next_stage))
+                .next();
+        }
+        chain
+    }
+
+    /// Every task that needs another one which won't have run before it.
+    pub fn check_dependencies(&self) -> Vec<String> {
+        let chain = self.chain();
+        let mut problems = Vec::new();
+        let mut names: Vec<&String> = self.map.keys().collect();
+        names.sort();
+
+        for stage in names {
+            let done_before: Vec<&String> = match chain.iter().position(|s| s == stage) {
+                Some(pos) => chain[..pos].iter().flat_map(|s| &self.map[*s]).collect(),
+                None => Vec::new(),
+            };
+            let tasks = &self.map[stage];
+            for (i, task) in tasks.iter().enumerate() {
+                let requires = TASKS.get(task).map_or(&[][..], |t| t.requires);
+                for dep in requires {
+                    if done_before.iter().any(|t| t == dep) || tasks[..i].iter().any(|t| t == dep)
+                    {
+                        continue;
+                    }
+                    if tasks[i..].iter().any(|t| t == dep) {
+                        problems.push(format!(
+                            "task {} in stage {} has to run after {}",
+                            task, stage, dep
+                        ));
+                    } else {
+                        problems.push(format!(
+                            "task {} in stage {} requires {}, which does not run before it",
+                            task, stage, dep
+                        ));
+                    }
+                }
+            }
+        }
+        problems
+    }
+
+    /// Moves tasks after the tasks of the same stage they depend on,
+    /// otherwise keeping the configured order.
+    pub fn auto_order(&mut self) {
+        for tasks in self.map.values_mut() {
+            let mut left = std::mem::take(tasks);
+            while !left.is_empty() {
+                let ready = left
+                    .iter()
+                    .position(|task| {
+                        TASKS.get(task).is_none_or(|t| {
+                            t.requires.iter().all(|dep| !left.iter().any(|d| d == dep))
+                        })
+                    })
+                    // dependencies of tasks are acyclic, but never loop forever
+                    .unwrap_or(0);
+                tasks.push(left.remove(ready));
+            }
+        }
+    }
 }
 
 impl Default for Config {
     fn default() -> Config {
         let mut m = HashMap::new();
-        let s1 = vec![TASKS["prepare"], TASKS["base"]];
-        m.insert("my_stage1".to_string(), s1);
-        let s2 = vec![TASKS["bootloader"]];
+        let s1 = ["prepare", "download_base", "base", "bootloader"]
+            .iter()
+            .map(|t| t.to_string())
+            .collect();
+        m.insert("my_first_stage".to_string(), s1);
+        let s2 = vec!["essential_packages".to_string()];
         m.insert("my_stage2".to_string(), s2);
         Config {
             installer: Installer {
                 system_disk: "/dev/sdXn".into(),
                 boot_disk: "/dev/sdXn".into(),
                 install_disk: "/dev/sdX".into(),
+                expected_model: None,
+                expected_size: None,
+                log_dir: None,
+                partition_table: PartitionTable::Gpt,
+                system_filesystem: None,
+                partitions: Vec::new(),
+                encryption: None,
+                lvm: None,
+                btrfs: Btrfs::default(),
             },
             user: User {
                 name: "your_login".to_string(),
                 full_name: "Full Name".to_string(),
                 email: "my@email.com".to_string(),
                 hostname: "myhost".to_string(),
+                dotfiles: None,
             },
+            swap: None,
+            system: System::default(),
+            hardware: Hardware::default(),
+            bootloader: Bootloader::default(),
             stages: Stages {
                 first_stage: "my_first_stage".into(),
+                auto_order: false,
                 map: m,
             },
             path: PathBuf::default(),
@@ -70,24 +780,40 @@ impl Default for Config {
     }
 }
 
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&toml::to_string(self).map_err(|_| fmt::Error)?)
+    }
+}
+
 impl Config {
-    pub fn to_string(&self) -> String {
-        toml::to_string(self).unwrap()
+    pub fn log_dir(&self) -> PathBuf {
+        match &self.installer.log_dir {
+            Some(dir) => dir.clone(),
+            None => self.path.with_file_name("logs"),
+        }
     }
 }
 
-pub fn load_config(path: PathBuf) -> Result<Config, std::io::Error> {
+/// Parses the config file without validating it.
+pub fn read_config(path: PathBuf) -> Result<Config, std::io::Error> {
     let mut cfg_file = File::open(&path)?;
     let mut cfg_str = String::new();
     cfg_file.read_to_string(&mut cfg_str)?;
     let mut config: Config = toml::from_str(&cfg_str)?;
 
-    if !config.stages.map.contains_key(&config.stages.first_stage) {
-        error(&format!(
-            "first stage '{}' does not exist",
-            config.stages.first_stage
-        ));
-        std::process::exit(1);
+    config.installer.resolve_devices();
+    if let Some(Swap::Partition { device, .. }) = &mut config.swap {
+        *device = disk::resolve(device);
+    }
+    config.installer.resolve_partitions();
+    if let Some(Swap::File { path, .. }) = &mut config.swap {
+        if path.as_os_str().is_empty() {
+            *path = config.installer.default_swapfile();
+        }
+    }
+    if config.stages.auto_order {
+        config.stages.auto_order();
     }
 
     config.path = path;
@@ -95,101 +821,86 @@ pub fn load_config(path: PathBuf) -> Result<Config, std::io::Error> {
     Ok(config)
 }
 
-mod stages_map {
-    use super::{StagesMap, TASKS};
-    use crate::tasks::Task;
-    use serde::de::Deserializer;
-    use serde::ser::Serializer;
-    use serde::{Deserialize, Serialize};
-    use std::collections::HashMap;
+/// Exits with all problems found in the config, except those that only
+/// matter before installation starts. Unless `full` only the stages are
+/// checked: after the live ISO the rest describes disks and firmware of
+/// a machine state that is gone.
+pub fn check_config(config: &Config, full: bool) {
+    let problems = if full {
+        validate(config, false)
+    } else {
+        validate_stages(config)
+    };
+    if !problems.is_empty() {
+        for problem in problems {
+            error(&problem.message);
+        }
+        std::process::exit(1);
+    }
+}
 
-    struct Wrapper(Task);
+/// Devices are given as kernel names like `sda2`, as paths like
+/// `/dev/disk/by-id/...` or as `UUID=`, `PARTUUID=`, `LABEL=` or `PARTLABEL=`.
+mod device_path_from_name {
+    use serde::{self, Deserialize, Deserializer, Serializer};
+    use std::path::{Path, PathBuf};
 
-    pub fn deserialize<'de, D>(deserializer: D) -> Result<StagesMap, D::Error>
+    pub fn serialize<S>(path: &Path, serializer: S) -> Result<S::Ok, S::Error>
     where
-        D: Deserializer<'de>,
+        S: Serializer,
     {
-        let tm = HashMap::<String, Vec<Wrapper>>::deserialize(deserializer)?;
-        Ok(tm
-            .into_iter()
-            .map(|(k, v)| (k, v.into_iter().map(|Wrapper(i)| i).collect()))
-            .collect())
+        let spec = path
+            .to_str()
+            .ok_or_else(|| serde::ser::Error::custom("invalid device path"))?;
+        match path.strip_prefix("/dev") {
+            Ok(name) if name.components().count() == 1 => {
+                serializer.serialize_str(&name.to_string_lossy())
+            }
+            _ => serializer.serialize_str(spec),
+        }
     }
 
-    pub fn serialize<S>(m: &StagesMap, serializer: S) -> Result<S::Ok, S::Error>
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<PathBuf, D::Error>
     where
-        S: Serializer,
+        D: Deserializer<'de>,
     {
-        let hm: HashMap<_, Vec<_>> = m
-            .iter()
-            .map(|(k, v)| (k, v.iter().map(|v| Wrapper(*v)).collect()))
-            .collect();
-
-        hm.serialize(serializer)
+        let spec = String::deserialize(deserializer)?;
+        Ok(from_spec(&spec))
     }
 
-    impl<'de> Deserialize<'de> for Wrapper {
-        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-        where
-            D: Deserializer<'de>,
-        {
-            let s = String::deserialize(deserializer)?;
-            match TASKS.get(&s) {
-                Some(task) => Ok(Wrapper(task.clone())),
-                None => Err(serde::de::Error::custom(format!("there is no task {}", s))),
-            }
-        }
-    }
-    impl Serialize for Wrapper {
-        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-        where
-            S: Serializer,
-        {
-            if let Some((k, _)) = TASKS
-                .iter()
-                // this might not work
-                .find(|(k, v)| (**v) as *const fn() == self.0 as *const fn())
-            {
-                String::serialize(k, serializer)
-            } else {
-                Err(serde::ser::Error::custom("failed to serialize task name"))
-            }
+    pub fn from_spec(spec: &str) -> PathBuf {
+        if spec.is_empty() || spec.starts_with('/') || spec.contains('=') {
+            spec.into()
+        } else {
+            Path::new("/dev").join(spec)
         }
     }
 }
 
-mod existing_device_path_from_name {
-    use serde::{self, Deserialize, Deserializer, Serializer};
+mod device_paths_from_names {
+    use super::device_path_from_name::from_spec;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
     use std::path::PathBuf;
 
-    pub fn serialize<S>(path: &PathBuf, serializer: S) -> Result<S::Ok, S::Error>
+    pub fn serialize<S>(paths: &[PathBuf], serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        let filename = path
-            .file_name()
-            .map(|os| os.to_str())
-            .flatten()
-            .ok_or_else(|| serde::ser::Error::custom("invalid filename"))?;
-        serializer.serialize_str(&filename)
+        let specs: Vec<_> = paths
+            .iter()
+            .map(|p| match p.strip_prefix("/dev") {
+                Ok(name) if name.components().count() == 1 => name.to_string_lossy(),
+                _ => p.to_string_lossy(),
+            })
+            .collect();
+        specs.serialize(serializer)
     }
 
-    pub fn deserialize<'de, D>(deserializer: D) -> Result<PathBuf, D::Error>
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<PathBuf>, D::Error>
     where
         D: Deserializer<'de>,
     {
-        let dev_name = String::deserialize(deserializer)?;
-        let mut pb = PathBuf::new();
-        pb.push("/dev");
-        pb.push(&dev_name);
-        let p = pb.as_path();
-        if p.exists() && !p.is_dir() {
-            Ok(pb)
-        } else {
-            Err(serde::de::Error::custom(format!(
-                "device {} doesn't exist",
-                dev_name
-            )))
-        }
+        let specs = Vec::<String>::deserialize(deserializer)?;
+        Ok(specs.iter().map(|s| from_spec(s)).collect())
     }
 }
@@ -0,0 +1,292 @@
+use crate::config::{
+    BootloaderKind, Config, CpuVendor, Filesystem, Gpu, Partition, PartitionTable, Stages,
+};
+use crate::disk;
+use crate::safety;
+use crate::InstallError;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs::{read_dir, read_to_string};
+use std::path::Path;
+
+/// What `detect` found out about the machine it runs on.
+pub struct Detected {
+    pub disks: Vec<Disk>,
+    pub efi: bool,
+    pub cpu: Option<CpuVendor>,
+    pub gpu: Gpu,
+    pub audio: bool,
+    pub wifi: Vec<String>,
+    pub battery: bool,
+}
+
+#[derive(Deserialize)]
+pub struct Disk {
+    pub name: String,
+    pub size: u64,
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// removable
+    pub rm: bool,
+    pub model: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Lsblk {
+    blockdevices: Vec<Disk>,
+}
+
+pub fn detect() -> Result<Detected, InstallError> {
+    let lsblk = run!("lsblk", "-J", "-b", "-d", "-o", "NAME,SIZE,TYPE,RM,MODEL").read()?;
+    let lspci = run!("lspci").read()?;
+    Ok(Detected {
+        disks: parse_lsblk(&lsblk)?,
+        efi: Path::new("/sys/firmware/efi").exists(),
+        cpu: cpu_vendor(&read_to_string("/proc/cpuinfo").unwrap_or_default()),
+        gpu: gpu(&lspci),
+        audio: !entries("/sys/class/sound", |name, _| name.starts_with("card")).is_empty(),
+        wifi: entries("/sys/class/net", |_, path| path.join("wireless").exists()),
+        battery: !entries("/sys/class/power_supply", |_, path| {
+            read_to_string(path.join("type")).is_ok_and(|t| t.trim() == "Battery")
+        })
+        .is_empty(),
+    })
+}
+
+fn parse_lsblk(output: &str) -> Result<Vec<Disk>, InstallError> {
+    serde_json::from_str::<Lsblk>(output)
+        .map(|lsblk| lsblk.blockdevices)
+        .map_err(|err| InstallError::InvalidFile(format!("lsblk output: {}", err)))
+}
+
+/// Names of the entries of `dir` that `filter` accepts.
+fn entries(dir: &str, filter: impl Fn(&str, &Path) -> bool) -> Vec<String> {
+    let mut names: Vec<String> = read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| {
+            (
+                entry.file_name().to_string_lossy().into_owned(),
+                entry.path(),
+            )
+        })
+        .filter(|(name, path)| filter(name, path))
+        .map(|(name, _)| name)
+        .collect();
+    names.sort();
+    names
+}
+
+/// Vendor from the `vendor_id` line of `/proc/cpuinfo`.
+pub fn cpu_vendor(cpuinfo: &str) -> Option<CpuVendor> {
+    let vendor = cpuinfo
+        .lines()
+        .find(|line| line.starts_with("vendor_id"))?
+        .split(':')
+        .nth(1)?
+        .trim();
+    match vendor {
+        "GenuineIntel" => Some(CpuVendor::Intel),
+        "AuthenticAMD" => Some(CpuVendor::Amd),
+        _ => None,
+    }
+}
+
+/// The GPU that needs the most specific driver, hybrid laptops get the
+/// discrete one.
+pub fn gpu(lspci: &str) -> Gpu {
+    let displays: Vec<&str> = lspci
+        .lines()
+        .filter(|line| {
+            line.contains("VGA compatible controller")
+                || line.contains("3D controller")
+                || line.contains("Display controller")
+        })
+        .collect();
+    let any = |vendor: &str| displays.iter().any(|line| line.contains(vendor));
+    if any("NVIDIA") {
+        Gpu::Nvidia
+    } else if any("AMD") || any("ATI") {
+        Gpu::Amd
+    } else if any("Intel") {
+        Gpu::Intel
+    } else {
+        Gpu::Vesa
+    }
+}
+
+impl Detected {
+    /// The biggest fixed disk that isn't the live boot medium.
+    pub fn install_disk(&self) -> Option<&Disk> {
+        let boot_medium = safety::boot_medium().map(|name| safety::disk_of(&name));
+        self.disks
+            .iter()
+            .filter(|disk| disk.kind == "disk" && !disk.rm)
+            .filter(|disk| !disk.name.starts_with("zram"))
+            .filter(|disk| boot_medium.as_deref() != Some(&disk.name))
+            .max_by_key(|disk| disk.size)
+    }
+
+    /// Facts that didn't make it into the config, for the reader to check.
+    pub fn summary(&self) -> Vec<String> {
+        let mut lines = vec!["disks:".to_string()];
+        for disk in &self.disks {
+            lines.push(format!(
+                "  {} {} GiB {}{}",
+                disk.name,
+                disk.size >> 30,
+                disk.model.as_deref().unwrap_or("").trim(),
+                if disk.rm { " (removable)" } else { "" }
+            ));
+        }
+        if !self.efi {
+            lines.push("no UEFI firmware found, booting with grub".to_string());
+        }
+        lines.push(format!("gpu: {}", format!("{:?}", self.gpu).to_lowercase()));
+        if !self.wifi.is_empty() {
+            lines.push(format!("wifi: {}", self.wifi.join(", ")));
+        }
+        lines.push(format!("audio: {}", if self.audio { "yes" } else { "no" }));
+        lines.push(format!(
+            "battery: {}",
+            if self.battery { "yes" } else { "no" }
+        ));
+        lines
+    }
+
+    /// Config for this machine, with the usual stages and the tasks that
+    /// make sense for its hardware.
+    pub fn suggest(&self) -> Config {
+        let mut config = Config::default();
+        let installer = &mut config.installer;
+        if let Some(disk) = self.install_disk() {
+            installer.install_disk = Path::new("/dev").join(&disk.name);
+        }
+        installer.system_disk = Default::default();
+        installer.boot_disk = Default::default();
+        installer.partitions = vec![
+            Partition {
+                size: Some("512MiB".to_string()),
+                type_guid: Some(disk::ESP.to_string()),
+                label: Some("boot".to_string()),
+                filesystem: Some(Filesystem::Fat32),
+                mount_point: Some("/boot".to_string()),
+            },
+            Partition {
+                size: None,
+                type_guid: None,
+                label: Some("system".to_string()),
+                filesystem: Some(Filesystem::Ext4),
+                mount_point: Some("/".to_string()),
+            },
+        ];
+        if !self.efi {
+            installer.partition_table = PartitionTable::Dos;
+            installer.partitions[0].type_guid = None;
+            config.bootloader.kind = BootloaderKind::Grub;
+        }
+        config.hardware.cpu = self.cpu;
+        config.hardware.gpu = self.gpu;
+        config.stages = self.stages();
+        config
+    }
+
+    fn stages(&self) -> Stages {
+        let boot_kernel = vec![
+            "prepare",
+            "download_base",
+            "base",
+            "bootloader",
+            "setup_reboot_user_system",
+            "reboot",
+        ];
+        let mut user_system = vec![
+            "cleanup_reboot_hook",
+            "systemd_network",
+            "essential_packages",
+            "terminal_packages",
+            "add_user",
+            "generate_ssh_keys",
+            "vga",
+        ];
+        if self.audio {
+            user_system.push("audio");
+        }
+        user_system.extend(&[
+            "desktop",
+            "desktop_packages",
+            "codecs",
+            "setup_dotfiles",
+            "disable_root_login",
+        ]);
+        if self.battery {
+            user_system.push("power_management");
+        }
+        user_system.extend(&["firewall", "setup_reboot_post_install", "reboot"]);
+        let post_install = vec!["set_git_user", "rust_packages"];
+
+        let mut map = HashMap::new();
+        for (name, tasks) in [
+            ("boot_kernel", boot_kernel),
+            ("user_system", user_system),
+            ("post_install", post_install),
+        ] {
+            map.insert(
+                name.to_string(),
+                tasks.iter().map(|t| t.to_string()).collect(),
+            );
+        }
+        Stages {
+            first_stage: "boot_kernel".to_string(),
+            auto_order: false,
+            map,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hybrid_laptop_gets_discrete_gpu() {
+        let lspci = "00:02.0 VGA compatible controller: Intel Corporation UHD Graphics 620
+01:00.0 3D controller: NVIDIA Corporation GP108M [GeForce MX150]
+00:1f.3 Audio device: Intel Corporation Sunrise Point-LP HD Audio";
+        assert_eq!(gpu(lspci), Gpu::Nvidia);
+        assert_eq!(
+            gpu("00:02.0 VGA compatible controller: Intel Corporation"),
+            Gpu::Intel
+        );
+        assert_eq!(gpu(""), Gpu::Vesa);
+    }
+
+    #[test]
+    fn biggest_fixed_disk_is_installed_to() {
+        let lsblk = r#"{"blockdevices": [
+            {"name":"sda", "size":32015679488, "type":"disk", "rm":true, "model":"USB Stick"},
+            {"name":"nvme0n1", "size":512110190592, "type":"disk", "rm":false, "model":"Samsung SSD 970"},
+            {"name":"sdb", "size":256060514304, "type":"disk", "rm":false, "model":null},
+            {"name":"loop0", "size":838860800, "type":"loop", "rm":false, "model":null}
+        ]}"#;
+        let detected = Detected {
+            disks: parse_lsblk(lsblk).unwrap(),
+            efi: true,
+            cpu: cpu_vendor("processor\t: 0\nvendor_id\t: AuthenticAMD\n"),
+            gpu: Gpu::Amd,
+            audio: false,
+            wifi: vec!["wlan0".to_string()],
+            battery: true,
+        };
+
+        let config = detected.suggest();
+        assert_eq!(config.installer.install_disk, Path::new("/dev/nvme0n1"));
+        assert_eq!(config.hardware.cpu, Some(CpuVendor::Amd));
+        assert_eq!(config.hardware.gpu, Gpu::Amd);
+        let user_system = &config.stages.map["user_system"];
+        assert!(user_system.contains(&"power_management".to_string()));
+        assert!(!user_system.contains(&"audio".to_string()));
+        assert!(config.stages.check_dependencies().is_empty());
+    }
+}
@@ -0,0 +1,334 @@
+use crate::config::{Encryption, Filesystem, Installer, Lvm, PartitionTable, Swap};
+use crate::utils::make_dir;
+use crate::InstallError;
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+
+/// Tags `blkid` can look devices up by.
+const TAGS: [&str; 4] = ["UUID=", "PARTUUID=", "LABEL=", "PARTLABEL="];
+
+/// Kernel device node for specs like `UUID=...` or `/dev/disk/by-id/...`;
+/// the spec itself if there is no such device (yet).
+pub fn resolve(device: &Path) -> PathBuf {
+    let spec = device.to_string_lossy();
+    if !TAGS.iter().any(|tag| spec.starts_with(tag)) {
+        return device.canonicalize().unwrap_or_else(|_| device.to_owned());
+    }
+    match run!("blkid", "-o", "device", "-t", &*spec).read() {
+        Ok(node) if !node.is_empty() => node.into(),
+        _ => device.to_owned(),
+    }
+}
+
+/// Value of `tag` (like `UUID`) of `device`, empty if it has none.
+pub fn blkid(tag: &str, device: &Path) -> Result<String, InstallError> {
+    match run!("blkid", "-s", tag, "-o", "value", device).read() {
+        // blkid exits with 2 when the tag is missing
+        Err(InstallError::CommandFailed { .. }) => Ok(String::new()),
+        result => result,
+    }
+}
+
+/// GPT partition type of the EFI system partition.
+pub const ESP: &str = "C12A7328-F81F-11D2-BA4B-00A0C93EC93B";
+
+/// Input for `sfdisk` that creates `installer.partitions` on an empty table.
+pub fn sfdisk_script(installer: &Installer) -> String {
+    let mut script = match installer.partition_table {
+        PartitionTable::Gpt => "label: gpt\n".to_string(),
+        PartitionTable::Dos => "label: dos\n".to_string(),
+    };
+    for partition in &installer.partitions {
+        let mut fields = Vec::new();
+        if let Some(size) = &partition.size {
+            fields.push(format!("size={}", size));
+        }
+        let type_guid = match (partition.mount_point.as_deref(), installer.partition_table) {
+            (Some("/boot"), PartitionTable::Gpt) => partition.type_guid.as_deref().or(Some(ESP)),
+            _ => partition.type_guid.as_deref(),
+        };
+        if let Some(type_guid) = type_guid {
+            fields.push(format!("type={}", type_guid));
+        }
+        if let (Some(label), PartitionTable::Gpt) = (&partition.label, installer.partition_table) {
+            fields.push(format!("name=\"{}\"", label));
+        }
+        if fields.is_empty() {
+            // a blank line would be ignored, this one takes all defaults
+            script.push_str(",\n");
+        } else {
+            script.push_str(&fields.join(", "));
+            script.push('\n');
+        }
+    }
+    script
+}
+
+/// Creates `filesystem` on `device`, overwriting whatever is there.
+pub fn format(device: &Path, filesystem: Filesystem) -> Result<(), InstallError> {
+    let desc = format!("formatting {} as {:?}", device.display(), filesystem);
+    match filesystem {
+        Filesystem::Fat32 => run!("mkfs.fat", "-F32", device),
+        Filesystem::Ext4 => run!("mkfs.ext4", device),
+        Filesystem::Btrfs => run!("mkfs.btrfs", "-f", device),
+        Filesystem::Xfs => run!("mkfs.xfs", "-f", device),
+    }
+    .desc(desc)
+    .run()
+}
+
+/// Formats `device` as LUKS and opens it as `/dev/mapper/<name>`.
+pub fn encrypt(
+    device: &Path,
+    encryption: &Encryption,
+    passphrase: &str,
+) -> Result<(), InstallError> {
+    run!(
+        "cryptsetup",
+        "luksFormat",
+        "--batch-mode",
+        "--key-file=-",
+        device
+    )
+    .desc(format!("encrypting {}", device.display()))
+    .run_with_secret(passphrase)?;
+    run!(
+        "cryptsetup",
+        "open",
+        "--key-file=-",
+        device,
+        &encryption.name
+    )
+    .desc(format!(
+        "opening {} as {}",
+        device.display(),
+        encryption.name
+    ))
+    .run_with_secret(passphrase)
+}
+
+/// Creates the volume group with its logical volumes and formats them.
+pub fn create_volumes(installer: &Installer, lvm: &Lvm) -> Result<(), InstallError> {
+    let physical_volumes = installer.physical_volumes();
+    run!("pvcreate", "--yes")
+        .args(&physical_volumes)
+        .desc("creating physical volumes")
+        .run()?;
+    run!("vgcreate", &lvm.volume_group)
+        .args(&physical_volumes)
+        .desc(format!("creating volume group {}", lvm.volume_group))
+        .run()?;
+
+    for volume in &lvm.volumes {
+        let size = if volume.size.contains('%') {
+            "-l"
+        } else {
+            "-L"
+        };
+        run!(
+            "lvcreate",
+            "--yes",
+            size,
+            &volume.size,
+            "-n",
+            &volume.name,
+            &lvm.volume_group
+        )
+        .desc(format!("creating logical volume {}", volume.name))
+        .run()?;
+        let filesystem = match volume.mount_point.as_deref() {
+            Some("/") => Some(installer.system_filesystem()),
+            _ => volume.filesystem,
+        };
+        if let Some(filesystem) = filesystem {
+            format(&lvm.volume_path(volume), filesystem)?;
+        }
+    }
+    Ok(())
+}
+
+/// Creates and enables swap of the new system, so that `genfstab` picks it up;
+/// zram is only configured inside the system.
+pub fn create_swap(swap: &Swap, filesystem: Filesystem) -> Result<(), InstallError> {
+    match swap {
+        Swap::Partition { device, .. } => {
+            run!("mkswap", device)
+                .desc(format!("creating swap on {}", device.display()))
+                .run()?;
+            run!("swapon", device).run()
+        }
+        Swap::File { size, path, .. } => {
+            let file = Path::new("/mnt").join(path.strip_prefix("/").unwrap_or(path));
+            run!("truncate", "-s", "0", &file).run()?;
+            if filesystem == Filesystem::Btrfs {
+                // swap files on btrfs must not be copy-on-write
+                run!("chattr", "+C", &file).run()?;
+            }
+            run!("fallocate", "-l", size, &file)
+                .desc(format!("allocating {} swap file", size))
+                .run()?;
+            run!("chmod", "600", &file).run()?;
+            run!("mkswap", &file).run()?;
+            run!("swapon", &file).run()
+        }
+        Swap::Zram { .. } => Ok(()),
+    }
+}
+
+pub struct Mount {
+    pub device: PathBuf,
+    /// Mount point inside the new system.
+    pub mount_point: String,
+    pub options: Option<String>,
+}
+
+/// Mounts a filesystem of the new system under `/mnt`.
+pub fn mount(m: &Mount) -> Result<(), InstallError> {
+    let target: PathBuf = Path::new("/mnt").join(m.mount_point.trim_start_matches('/'));
+    make_dir(&target)?;
+    match &m.options {
+        Some(options) => run!("mount", "-o", options, &m.device, &target).run(),
+        None => run!("mount", &m.device, &target).run(),
+    }
+}
+
+/// Creates the configured btrfs subvolumes on the freshly formatted root device.
+pub fn create_subvolumes(installer: &Installer) -> Result<(), InstallError> {
+    run!("mount", installer.root_device(), "/mnt").run()?;
+    for subvolume in &installer.btrfs.subvolumes {
+        run!("btrfs", "subvolume", "create", format!("/mnt/{}", subvolume.name))
+            .desc(format!("creating subvolume {}", subvolume.name))
+            .run()?;
+    }
+    run!("umount", "/mnt").run()
+}
+
+/// Everything that has to be mounted for the installation, parents first.
+pub fn mounts(installer: &Installer) -> Vec<Mount> {
+    let mut mounts = Vec::new();
+    if installer.system_filesystem() == Filesystem::Btrfs {
+        for subvolume in &installer.btrfs.subvolumes {
+            let mut options = format!("subvol={}", subvolume.name);
+            if let Some(extra) = &subvolume.options {
+                options = format!("{},{}", options, extra);
+            }
+            mounts.push(Mount {
+                device: installer.root_device(),
+                mount_point: subvolume.mount_point.clone(),
+                options: Some(options),
+            });
+        }
+    } else {
+        mounts.push(Mount {
+            device: installer.root_device(),
+            mount_point: "/".to_string(),
+            options: None,
+        });
+    }
+
+    if installer.partitions.is_empty() {
+        mounts.push(Mount {
+            device: installer.boot_disk.clone(),
+            mount_point: "/boot".to_string(),
+            options: None,
+        });
+    }
+    if let Some(lvm) = &installer.lvm {
+        for volume in &lvm.volumes {
+            match volume.mount_point.as_deref() {
+                Some("/") | None => {}
+                Some(m) => mounts.push(Mount {
+                    device: lvm.volume_path(volume),
+                    mount_point: m.to_string(),
+                    options: None,
+                }),
+            }
+        }
+    }
+    for (device, partition) in installer.partition_devices() {
+        match partition.mount_point.as_deref() {
+            Some("/") | None => {}
+            Some(m) => mounts.push(Mount {
+                device,
+                mount_point: m.to_string(),
+                options: None,
+            }),
+        }
+    }
+
+    mounts.sort_by_key(|m| m.mount_point.trim_end_matches('/').matches('/').count());
+    mounts
+}
+
+/// Kernel name of a block device, following symlinks like `/dev/disk/by-id/...`.
+pub fn block_name(device: &Path) -> String {
+    let device = device.canonicalize().unwrap_or_else(|_| device.to_owned());
+    device
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+pub fn is_partition_of(partition: &str, disk: &str) -> bool {
+    Path::new("/sys/class/block")
+        .join(disk)
+        .join(partition)
+        .join("partition")
+        .exists()
+}
+
+/// Devices that are mounted or used as swap.
+pub fn mounted_devices() -> Vec<PathBuf> {
+    let mounts = read_to_string("/proc/mounts").unwrap_or_default();
+    let swaps = read_to_string("/proc/swaps").unwrap_or_default();
+    mounts
+        .lines()
+        .chain(swaps.lines().skip(1))
+        .filter_map(|line| line.split_whitespace().next())
+        .filter(|source| source.starts_with("/dev/"))
+        .map(PathBuf::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, Partition};
+
+    #[test]
+    fn boot_partition_defaults_to_esp() {
+        let mut config = Config::default();
+        config.installer.partitions = vec![
+            Partition {
+                size: Some("512MiB".to_string()),
+                type_guid: None,
+                label: None,
+                filesystem: None,
+                mount_point: Some("/boot".to_string()),
+            },
+            Partition {
+                size: None,
+                type_guid: None,
+                label: None,
+                filesystem: None,
+                mount_point: Some("/".to_string()),
+            },
+        ];
+
+        assert_eq!(
+            sfdisk_script(&config.installer),
+            format!("label: gpt\nsize=512MiB, type={}\n,\n", ESP)
+        );
+    }
+
+    #[test]
+    fn btrfs_swap_file_gets_own_subvolume() {
+        let mut installer = Config::default().installer;
+        assert_eq!(installer.default_swapfile(), Path::new("/swapfile"));
+
+        installer.system_filesystem = Some(Filesystem::Btrfs);
+        assert_eq!(installer.default_swapfile(), Path::new("/swap/swapfile"));
+        assert!(mounts(&installer).iter().any(|m| m.mount_point == "/swap"
+            && m.options.as_deref() == Some("subvol=@swap,noatime,compress=zstd")));
+    }
+}
@@ -1,11 +1,26 @@
-use crate::config::Config;
+use crate::config::{
+    BootloaderKind, Config, CpuVendor, Filesystem, Firmware, Initramfs, Installer, Kernel,
+    Microcode, Swap,
+};
+use crate::detect;
+use crate::disk;
+use crate::run::path_exists;
+use crate::safety;
+use crate::state::handoff;
 use crate::utils::*;
 use crate::{confirm, InstallError};
-use std::fs::{copy, create_dir_all};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-pub fn download_base(_: &Config) -> Result<(), InstallError> {
-    run!("pacstrap", "/mnt", "base", "linux", "linux-firmware")
+pub fn download_base(config: &Config) -> Result<(), InstallError> {
+    let mut packages = vec!["base", "linux-firmware"];
+    packages.extend(config.bootloader.kernels.iter().map(|k| k.name.as_str()));
+    match config.installer.system_filesystem() {
+        Filesystem::Btrfs => packages.push("btrfs-progs"),
+        Filesystem::Xfs => packages.push("xfsprogs"),
+        Filesystem::Ext4 | Filesystem::Fat32 => {}
+    }
+    run!("pacstrap", "/mnt")
+        .args(packages)
         .desc("installing essential packages")
         .run()
 }
@@ -18,11 +33,12 @@ pub fn base(config: &Config) -> Result<(), InstallError> {
     with_chroot(config, "base_in_chroot")
 }
 
-pub fn base_in_chroot(_: &Config) -> Result<(), InstallError> {
+pub fn base_in_chroot(config: &Config) -> Result<(), InstallError> {
+    let system = &config.system;
     run!(
         "ln",
         "-sf",
-        "/usr/share/zoneinfo/Europe/Moscow",
+        Path::new("/usr/share/zoneinfo").join(&system.timezone),
         "/etc/localtime"
     )
     .desc("setting timezone")
@@ -36,59 +52,606 @@ pub fn base_in_chroot(_: &Config) -> Result<(), InstallError> {
         .desc("setting Hardware Clock from Software Clock")
         .run()?;
 
+    for locale in &system.locales {
+        let present = match run!("grep", "-xF", locale, "/etc/locale.gen").read() {
+            // grep exits with 1 when nothing matches
+            Err(InstallError::CommandFailed { .. }) => String::new(),
+            result => result?,
+        };
+        if present.is_empty() {
+            append_to_file("/etc/locale.gen", locale)?;
+        }
+    }
     run!("locale-gen").desc("generating locale").run()?;
+    set_file("/etc/locale.conf", &format!("LANG={}\n", system.lang()))?;
 
-    set_file("/etc/hostname", "wpc")?;
+    let mut vconsole = format!("KEYMAP={}\n", system.keymap);
+    if let Some(font) = &system.font {
+        vconsole.push_str(&format!("FONT={}\n", font));
+    }
+    set_file("/etc/vconsole.conf", &vconsole)?;
+
+    let hostname = &config.user.hostname;
+    set_file("/etc/hostname", &format!("{}\n", hostname))?;
 
     append_to_file("/etc/hosts", "127.0.0.1\tlocalhost")?;
     append_to_file("/etc/hosts", "::1\t\tlocalhost")?;
-    append_to_file("/etc/hosts", "127.0.1.1\twpc.localdomain\twpc")?;
+    append_to_file("/etc/hosts", &hosts_entry(hostname))?;
 
-    run!("pacman", "--noconfirm", "-S", "intel-ucode")
-        .desc("install intel microcode")
-        .run()?;
+    let microcode = microcode(config)?;
+    if !microcode.is_empty() {
+        run!("pacman", "--noconfirm", "-S")
+            .args(&microcode)
+            .desc("install microcode")
+            .run()?;
+    }
+
+    if config.installer.lvm.is_some() {
+        run!("pacman", "--noconfirm", "-S", "lvm2")
+            .desc("install lvm2")
+            .run()?;
+    }
+    if let Some(Swap::Zram { size }) = &config.swap {
+        run!("pacman", "--noconfirm", "-S", "zram-generator")
+            .desc("install zram-generator")
+            .run()?;
+        set_file(
+            "/etc/systemd/zram-generator.conf",
+            &format!(
+                "[zram0]\nzram-size = {}\ncompression-algorithm = zstd\n",
+                size
+            ),
+        )?;
+    }
+
+    if needs_custom_hooks(config) {
+        let hooks = format!(
+            "s/^HOOKS=.*/HOOKS=({})/",
+            mkinitcpio_hooks(config).join(" ")
+        );
+        run!("sed", "-i", hooks, "/etc/mkinitcpio.conf")
+            .desc("adding initramfs hooks")
+            .run()?;
+        run!("mkinitcpio", "-P")
+            .desc("regenerating initramfs")
+            .run()?;
+    }
     Ok(())
 }
 
+/// Microcode packages to install, each ships `/boot/<package>.img`.
+fn microcode(config: &Config) -> Result<Vec<&'static str>, InstallError> {
+    let vendors = match config.hardware.microcode {
+        Microcode::Auto => match config.hardware.cpu {
+            Some(cpu) => vec![cpu],
+            None => {
+                let cpuinfo = match run!("grep", "-m1", "vendor_id", "/proc/cpuinfo").read() {
+                    // grep exits with 1 when nothing matches
+                    Err(InstallError::CommandFailed { .. }) => String::new(),
+                    result => result?,
+                };
+                let cpu = detect::cpu_vendor(&cpuinfo);
+                if cpu.is_none() {
+                    note("unknown CPU vendor, not installing microcode");
+                }
+                cpu.into_iter().collect()
+            }
+        },
+        Microcode::Intel => vec![CpuVendor::Intel],
+        Microcode::Amd => vec![CpuVendor::Amd],
+        Microcode::Both => vec![CpuVendor::Intel, CpuVendor::Amd],
+        Microcode::None => Vec::new(),
+    };
+    Ok(vendors
+        .into_iter()
+        .map(|cpu| match cpu {
+            CpuVendor::Intel => "intel-ucode",
+            CpuVendor::Amd => "amd-ucode",
+        })
+        .collect())
+}
+
+fn systemd_initramfs(config: &Config) -> bool {
+    config
+        .installer
+        .encryption
+        .as_ref()
+        .is_some_and(|e| e.initramfs == Initramfs::Systemd)
+}
+
+/// Whether the stock mkinitcpio hooks can't boot (or resume) this system.
+fn needs_custom_hooks(config: &Config) -> bool {
+    let resume = config.swap.as_ref().is_some_and(Swap::hibernate);
+    config.installer.encryption.is_some()
+        || config.installer.lvm.is_some()
+        || (resume && !systemd_initramfs(config))
+}
+
+fn mkinitcpio_hooks(config: &Config) -> Vec<&'static str> {
+    let systemd = systemd_initramfs(config);
+    let mut hooks = vec!["base"];
+    if systemd {
+        hooks.extend(&[
+            "systemd",
+            "autodetect",
+            "modconf",
+            "kms",
+            "keyboard",
+            "sd-vconsole",
+        ]);
+    } else {
+        hooks.extend(&[
+            "udev",
+            "autodetect",
+            "modconf",
+            "kms",
+            "keyboard",
+            "keymap",
+            "consolefont",
+        ]);
+    }
+    if config.bootloader.uki {
+        // unified images have no separate initrd for microcode; after
+        // autodetect the hook only packs the one for this CPU
+        let autodetect = hooks.iter().position(|h| *h == "autodetect").unwrap_or(0);
+        match config.hardware.microcode {
+            Microcode::Both => hooks.insert(autodetect, "microcode"),
+            _ => hooks.insert(autodetect + 1, "microcode"),
+        }
+    }
+    hooks.push("block");
+    if config.installer.encryption.is_some() {
+        hooks.push(if systemd { "sd-encrypt" } else { "encrypt" });
+    }
+    if config.installer.lvm.is_some() {
+        hooks.push("lvm2");
+    }
+    // the systemd hook resumes by itself
+    if !systemd && config.swap.as_ref().is_some_and(Swap::hibernate) {
+        hooks.push("resume");
+    }
+    hooks.extend(&["filesystems", "fsck"]);
+    hooks
+}
+
+/// Kernel command line that finds and, if needed, unlocks the root filesystem.
+pub fn kernel_options(config: &Config) -> Result<String, InstallError> {
+    Ok(kernel_params(config)?.join(" "))
+}
+
+/// Options the installation needs followed by `kernel_params` of the config.
+fn kernel_params(config: &Config) -> Result<Vec<String>, InstallError> {
+    let installer = &config.installer;
+    let mut options = Vec::new();
+    options.extend(unlock(installer)?);
+    options.push(format!("root={}", root_spec(installer)?));
+    options.push("rw".to_string());
+    options.extend(rootflags(config));
+    options.extend(resume(config)?);
+    merge_params(&mut options, &config.bootloader.kernel_params);
+    Ok(options)
+}
+
+/// Like `kernel_params`, without the root options `grub-mkconfig` adds itself.
+fn grub_params(config: &Config) -> Result<Vec<String>, InstallError> {
+    let params = &config.bootloader.kernel_params;
+    let mut options = Vec::new();
+    options.extend(unlock(&config.installer)?);
+    if params.iter().any(|p| param_name(p) == "rootflags") {
+        // the kernel only takes the last rootflags, they have to keep the subvolume
+        options.extend(rootflags(config));
+    }
+    options.extend(resume(config)?);
+    merge_params(&mut options, params);
+    Ok(options)
+}
+
+/// Option that opens the encrypted system disk.
+fn unlock(installer: &Installer) -> Result<Option<String>, InstallError> {
+    let encryption = match &installer.encryption {
+        Some(encryption) => encryption,
+        None => return Ok(None),
+    };
+    let uuid = disk::blkid("UUID", &installer.system_disk)?;
+    Ok(Some(match encryption.initramfs {
+        Initramfs::Busybox => format!("cryptdevice=UUID={}:{}", uuid, encryption.name),
+        Initramfs::Systemd => format!("rd.luks.name={}={}", uuid, encryption.name),
+    }))
+}
+
+/// Adds `params` to `options`; a param replaces the option with the same
+/// name, except for `rootflags` which are combined.
+fn merge_params(options: &mut Vec<String>, params: &[String]) {
+    // params may repeat among themselves, like `console=`
+    let given = options.len();
+    for param in params {
+        let name = param_name(param);
+        match options[..given]
+            .iter_mut()
+            .find(|option| param_name(option) == name)
+        {
+            Some(option) if name == "rootflags" => {
+                if let Some((_, flags)) = param.split_once('=') {
+                    option.push(',');
+                    option.push_str(flags);
+                }
+            }
+            Some(option) => *option = param.clone(),
+            None => options.push(param.clone()),
+        }
+    }
+}
+
+/// Name of a kernel param, like `rootflags` of `rootflags=subvol=@`.
+pub fn param_name(param: &str) -> &str {
+    param.split('=').next().unwrap_or(param)
+}
+
+/// Names the root filesystem in a way that doesn't depend on the order
+/// the kernel finds disks in.
+fn root_spec(installer: &Installer) -> Result<String, InstallError> {
+    if installer.encryption.is_none() && installer.lvm.is_none() {
+        let partuuid = disk::blkid("PARTUUID", &installer.system_disk)?;
+        // whole disks without a partition table have none
+        if !partuuid.is_empty() {
+            return Ok(format!("PARTUUID={}", partuuid));
+        }
+    }
+    Ok(format!(
+        "UUID={}",
+        disk::blkid("UUID", &installer.root_device())?
+    ))
+}
+
+/// Where the kernel finds the hibernation image.
+fn resume(config: &Config) -> Result<Vec<String>, InstallError> {
+    match &config.swap {
+        Some(Swap::Partition {
+            device,
+            hibernate: true,
+        }) => Ok(vec![format!(
+            "resume=UUID={}",
+            disk::blkid("UUID", device)?
+        )]),
+        Some(Swap::File {
+            path,
+            hibernate: true,
+            ..
+        }) => {
+            let offset = if config.installer.system_filesystem() == Filesystem::Btrfs {
+                run!("btrfs", "inspect-internal", "map-swapfile", "-r", path).read()?
+            } else {
+                first_extent(&run!("filefrag", "-v", path).read()?).unwrap_or_default()
+            };
+            Ok(vec![
+                format!("resume={}", root_spec(&config.installer)?),
+                format!("resume_offset={}", offset),
+            ])
+        }
+        _ => Ok(Vec::new()),
+    }
+}
+
+/// Physical offset of the first extent in the output of `filefrag -v`.
+fn first_extent(filefrag: &str) -> Option<String> {
+    filefrag
+        .lines()
+        .map(str::trim_start)
+        .find(|line| line.starts_with("0:"))
+        .and_then(|line| line.split_whitespace().nth(3))
+        .map(|offset| offset.trim_end_matches("..").to_string())
+}
+
+/// Mount options of the root filesystem that have to be given to the kernel.
+fn rootflags(config: &Config) -> Option<String> {
+    let installer = &config.installer;
+    match installer.btrfs.root() {
+        Some(root) if installer.system_filesystem() == Filesystem::Btrfs => {
+            Some(format!("rootflags=subvol={}", root.name))
+        }
+        _ => None,
+    }
+}
+
+/// `/etc/hosts` line resolving the machine's own name; a bare host name
+/// gets the `localdomain` domain.
+fn hosts_entry(hostname: &str) -> String {
+    match hostname.split('.').next() {
+        Some(short) if short != hostname => format!("127.0.1.1\t{}\t{}", hostname, short),
+        _ => format!("127.0.1.1\t{}.localdomain\t{}", hostname, hostname),
+    }
+}
+
 pub fn bootloader(config: &Config) -> Result<(), InstallError> {
     with_chroot(config, "bootloader_in_chroot")
 }
 
 pub fn bootloader_in_chroot(config: &Config) -> Result<(), InstallError> {
+    match config.bootloader.kind {
+        BootloaderKind::SystemdBoot => systemd_boot(config),
+        BootloaderKind::Grub => grub(config),
+    }
+}
+
+/// Whether the machine boots with UEFI rather than BIOS.
+fn uefi(config: &Config) -> bool {
+    match config.bootloader.firmware {
+        Firmware::Uefi => true,
+        Firmware::Bios => false,
+        Firmware::Auto => path_exists("/sys/firmware/efi"),
+    }
+}
+
+fn systemd_boot(config: &Config) -> Result<(), InstallError> {
+    if !uefi(config) {
+        return Err(InstallError::Custom(
+            "systemd-boot needs UEFI firmware, use grub on BIOS machines",
+        ));
+    }
+    let bootloader = &config.bootloader;
     run!("pacman", "--noconfirm", "-S", "efibootmgr").run()?;
+    if bootloader.secure_boot {
+        create_secure_boot_keys()?;
+    }
 
     run!("bootctl", "--path=/boot", "install")
         .desc("installing systemd-boot")
         .run()?;
 
-    set_file(
-        "/boot/loader/loader.conf",
-        "timeout 3
-console-mode keep
-default arch.conf
-editor=no",
-    )?;
+    let default = if bootloader.uki {
+        build_ukis(config)?;
+        format!("arch-{}.efi", bootloader.default_kernel())
+    } else {
+        boot_entries(config)?;
+        format!("arch-{}.conf", bootloader.default_kernel())
+    };
 
     set_file(
-        "/boot/loader/entries/arch.conf",
+        "/boot/loader/loader.conf",
         &format!(
-            r#"title Arch Linux
-linux /vmlinuz-linux
-initrd /intel-ucode.img
-initrd /initramfs-linux.img
-options root="{}" rw
-"#,
-            config.installer.system_disk.to_str().expect("system disk has to be valid utf8")
+            "timeout {}
+console-mode {}
+default {}
+editor {}
+",
+            bootloader.timeout,
+            bootloader.console_mode,
+            default,
+            if bootloader.editor { "yes" } else { "no" }
         ),
     )?;
 
+    if bootloader.secure_boot {
+        enroll_secure_boot(config)?;
+    }
+    Ok(())
+}
+
+/// Kernel command line of `kernel`, its own params after the common ones.
+fn kernel_cmdline(config: &Config, kernel: &Kernel) -> Result<String, InstallError> {
+    let mut options = kernel_params(config)?;
+    merge_params(&mut options, &kernel.params);
+    Ok(options.join(" "))
+}
+
+/// Writes `arch-<kernel>.conf` and `arch-<kernel>-fallback.conf` for every kernel.
+fn boot_entries(config: &Config) -> Result<(), InstallError> {
+    let microcode: String = microcode(config)?
+        .iter()
+        .map(|package| format!("initrd /{}.img\n", package))
+        .collect();
+    for kernel in &config.bootloader.kernels {
+        let options = kernel_cmdline(config, kernel)?;
+        for (suffix, title) in &[("", ""), ("-fallback", ", fallback initramfs")] {
+            set_file(
+                format!("/boot/loader/entries/arch-{}{}.conf", kernel.name, suffix),
+                &format!(
+                    r#"title Arch Linux ({name}{title})
+linux /vmlinuz-{name}
+{microcode}initrd /initramfs-{name}{suffix}.img
+options {options}
+"#,
+                    name = kernel.name,
+                    title = title,
+                    microcode = microcode,
+                    suffix = suffix,
+                    options = options
+                ),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Has mkinitcpio build `/boot/EFI/Linux/arch-<kernel>.efi` with the kernel,
+/// initramfs, microcode and kernel options; systemd-boot finds them without entries.
+fn build_ukis(config: &Config) -> Result<(), InstallError> {
+    set_file(
+        "/etc/kernel/cmdline",
+        &format!("{}\n", kernel_options(config)?),
+    )?;
+    make_dir("/boot/EFI/Linux")?;
+    for kernel in &config.bootloader.kernels {
+        let mut cmdline = None;
+        if !kernel.params.is_empty() {
+            let path = format!("/etc/kernel/cmdline-{}", kernel.name);
+            set_file(&path, &format!("{}\n", kernel_cmdline(config, kernel)?))?;
+            cmdline = Some(path);
+        }
+        set_file(
+            format!("/etc/mkinitcpio.d/{}.preset", kernel.name),
+            &uki_preset(&kernel.name, cmdline.as_deref()),
+        )?;
+    }
+    run!("mkinitcpio", "-P")
+        .desc("building unified kernel images")
+        .run()?;
+    // nothing boots them anymore and mkinitcpio won't update them
+    for kernel in &config.bootloader.kernels {
+        run!(
+            "rm",
+            "-f",
+            format!("/boot/initramfs-{}.img", kernel.name),
+            format!("/boot/initramfs-{}-fallback.img", kernel.name)
+        )
+        .run()?;
+    }
     Ok(())
 }
 
-#[must_use]
+/// Preset of `kernel`; without `cmdline` mkinitcpio uses `/etc/kernel/cmdline`.
+fn uki_preset(kernel: &str, cmdline: Option<&str>) -> String {
+    let cmdline = cmdline
+        .map(|path| format!(" --cmdline {}", path))
+        .unwrap_or_default();
+    format!(
+        r#"# mkinitcpio preset for the '{k}' package, written by the installer
+
+ALL_kver="/boot/vmlinuz-{k}"
+
+PRESETS=('default' 'fallback')
+
+default_uki="/boot/EFI/Linux/arch-{k}.efi"
+default_options="{c}"
+
+fallback_uki="/boot/EFI/Linux/arch-{k}-fallback.efi"
+fallback_options="-S autodetect{c}"
+"#,
+        k = kernel,
+        c = cmdline
+    )
+}
+
+const SYSTEMD_BOOT_EFI: &str = "/usr/lib/systemd/boot/efi/systemd-bootx64.efi";
+
+fn create_secure_boot_keys() -> Result<(), InstallError> {
+    run!("pacman", "--noconfirm", "-S", "sbctl").run()?;
+    run!("sbctl", "create-keys")
+        .desc("creating Secure Boot keys")
+        .run()?;
+    // bootctl installs and updates the signed copy when there is one
+    run!(
+        "sbctl",
+        "sign",
+        "-s",
+        "-o",
+        format!("{}.signed", SYSTEMD_BOOT_EFI),
+        SYSTEMD_BOOT_EFI
+    )
+    .desc("signing systemd-boot")
+    .run()
+}
+
+/// Signs the unified kernel images and enrolls the keys if the firmware lets us.
+fn enroll_secure_boot(config: &Config) -> Result<(), InstallError> {
+    // `-s` saves them in sbctl's database, its zz-sbctl.hook signs them
+    // again whenever a kernel update rebuilt them
+    for kernel in &config.bootloader.kernels {
+        for suffix in &["", "-fallback"] {
+            let image = format!("/boot/EFI/Linux/arch-{}{}.efi", kernel.name, suffix);
+            run!("sbctl", "sign", "-s", &image)
+                .desc(format!("signing {}", image))
+                .run()?;
+        }
+    }
+
+    let status = run!("sbctl", "status", "--json").read()?;
+    let setup_mode = serde_json::from_str::<serde_json::Value>(&status)
+        .ok()
+        .and_then(|status| status["setup_mode"].as_bool())
+        .unwrap_or(false);
+    if !setup_mode {
+        note("firmware is not in Secure Boot setup mode, enroll the keys later with `sbctl enroll-keys --microsoft`");
+        return Ok(());
+    }
+    // Microsoft's keys are kept, firmware of GPUs and other cards is signed with them
+    run!("sbctl", "enroll-keys", "--microsoft")
+        .desc("enrolling Secure Boot keys")
+        .run()
+}
+
+fn grub(config: &Config) -> Result<(), InstallError> {
+    if uefi(config) {
+        run!("pacman", "--noconfirm", "-S", "grub", "efibootmgr").run()?;
+        run!(
+            "grub-install",
+            "--target=x86_64-efi",
+            "--efi-directory=/boot",
+            "--bootloader-id=GRUB"
+        )
+        .desc("installing grub for UEFI")
+        .run()?;
+    } else {
+        let disk = &config.installer.install_disk;
+        run!("pacman", "--noconfirm", "-S", "grub").run()?;
+        run!("grub-install", "--target=i386-pc", disk)
+            .desc(format!("installing grub to the MBR of {}", disk.display()))
+            .run()?;
+    }
+
+    // grub-mkconfig finds the kernels, microcode images and root by itself;
+    // the options end up in a shell string, written by a sed replacement
+    let options = escape(&grub_params(config)?.join(" "), "\"\\$`");
+    let cmdline = format!(
+        "s|^GRUB_CMDLINE_LINUX=.*|GRUB_CMDLINE_LINUX=\"{}\"|",
+        escape(&options, "\\&|")
+    );
+    let timeout = format!(
+        "s|^GRUB_TIMEOUT=.*|GRUB_TIMEOUT={}|",
+        config.bootloader.timeout
+    );
+    run!(
+        "sed",
+        "-i",
+        "-e",
+        cmdline,
+        "-e",
+        timeout,
+        "/etc/default/grub"
+    )
+    .desc("setting kernel options and timeout")
+    .run()?;
+    if config.bootloader.kernels.len() > 1 {
+        // without the submenu every kernel is a top level entry, found by its title
+        let default = format!(
+            "s|^GRUB_DEFAULT=.*|GRUB_DEFAULT=\"Arch Linux, with Linux {}\"|",
+            config.bootloader.default_kernel()
+        );
+        run!(
+            "sed",
+            "-i",
+            "-e",
+            default,
+            "-e",
+            "s|^#\\?GRUB_DISABLE_SUBMENU=.*|GRUB_DISABLE_SUBMENU=y|",
+            "/etc/default/grub"
+        )
+        .desc("setting default kernel")
+        .run()?;
+    }
+    run!("grub-mkconfig", "-o", "/boot/grub/grub.cfg")
+        .desc("generating grub config")
+        .run()
+}
+
+/// Puts a backslash in front of every character of `special` in `s`.
+fn escape(s: &str, special: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if special.contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
 pub fn prepare(config: &Config) -> Result<(), InstallError> {
+    let installer = &config.installer;
     confirm("Are you connected to Internet")?;
-    confirm("Do you have your disks setup?")?;
+    if installer.partitions.is_empty() {
+        confirm("Do you have your disks setup?")?;
+    }
 
     run!("ip", "link").desc("Current network settings").run()?;
 
@@ -99,41 +662,102 @@ pub fn prepare(config: &Config) -> Result<(), InstallError> {
     note("Your disks:");
     run!("fdisk", "-l").run()?;
 
-    confirm(&format!(
-        "using {:?} as boot disk and {:?} as system disk, correct?",
-        config.installer.boot_disk, config.installer.system_disk,
-    ))?;
+    if !installer.partitions.is_empty() {
+        note(&format!(
+            "partition layout:\n{}",
+            disk::sfdisk_script(installer)
+        ));
+    }
+    safety::check(config)?;
 
-    run!("mkfs.fat", "-F32", &config.installer.boot_disk)
-        .desc("formatting boot disk")
-        .run()?;
-    run!("mkfs.ext4", &config.installer.system_disk)
-        .desc("formatting system disk")
-        .run()?;
+    if installer.partitions.is_empty() {
+        disk::format(&installer.boot_disk, Filesystem::Fat32)?;
+        format_system(installer)?;
+    } else {
+        partition(installer)?;
+    }
+    mount_all(installer)?;
+    create_swap(config)
+}
 
-    note("mounting disks");
-    run!("mount", &config.installer.system_disk, "/mnt").run()?;
+fn partition(installer: &Installer) -> Result<(), InstallError> {
+    run!("sfdisk", "--wipe", "always", &installer.install_disk)
+        .desc("partitioning install disk")
+        .run_with_stdin(disk::sfdisk_script(installer).as_bytes())?;
+    run!("udevadm", "settle").run()?;
 
-    run!("mkdir", "-p", "/mnt/boot").run()?;
+    for (device, partition) in installer.partition_devices() {
+        let filesystem = match (partition.filesystem, partition.mount_point.as_deref()) {
+            (_, Some("/")) => continue,
+            (Some(filesystem), _) => filesystem,
+            (None, Some("/boot")) => Filesystem::Fat32,
+            (None, _) => continue,
+        };
+        disk::format(&device, filesystem)?;
+    }
+    format_system(installer)
+}
+
+fn create_swap(config: &Config) -> Result<(), InstallError> {
+    match &config.swap {
+        Some(swap) => disk::create_swap(swap, config.installer.system_filesystem()),
+        None => Ok(()),
+    }
+}
 
-    run!("mount", &config.installer.boot_disk, "/mnt/boot").run()?;
+fn mount_all(installer: &Installer) -> Result<(), InstallError> {
+    if installer.system_filesystem() == Filesystem::Btrfs {
+        disk::create_subvolumes(installer)?;
+    }
+    note("mounting disks");
+    for mount in disk::mounts(installer) {
+        disk::mount(&mount)?;
+    }
     Ok(())
 }
+
+/// Formats the system partition, inside a LUKS container if encryption is on
+/// and split into logical volumes with LVM.
+fn format_system(installer: &Installer) -> Result<(), InstallError> {
+    let device = &installer.system_disk;
+    if let Some(encryption) = &installer.encryption {
+        let passphrase = password(&format!("Passphrase for {}", device.display()))?;
+        disk::encrypt(device, encryption, &passphrase)?;
+    }
+    match &installer.lvm {
+        Some(lvm) => disk::create_volumes(installer, lvm),
+        None => disk::format(&installer.root_device(), installer.system_filesystem()),
+    }
+}
+
 pub fn setup_reboot_user_system(config: &Config) -> Result<(), InstallError> {
     let wd = "/mnt/root/installer";
-    create_dir_all(wd)?;
-    copy(
+    make_dir(wd)?;
+    copy_file(
         &config.path,
-        &[wd, "config.yaml"].iter().collect::<PathBuf>(),
+        [wd, "config.yaml"].iter().collect::<PathBuf>(),
     )?;
 
-    copy(
-        &std::env::current_exe()?,
-        &[wd, "archinstaller"].iter().collect::<PathBuf>(),
+    copy_file(
+        std::env::current_exe()?,
+        [wd, "archinstaller"].iter().collect::<PathBuf>(),
     )?;
+    handoff(
+        config,
+        [wd, "state.toml"].iter().collect::<PathBuf>(),
+        "user_system",
+    )?;
+    run!(
+        "cp",
+        "-rT",
+        config.log_dir(),
+        [wd, "logs"].iter().collect::<PathBuf>()
+    )
+    .desc("copying session logs")
+    .run()?;
     set_file(
         "/mnt/root/continue_install.sh",
-        "/root/installer/archinstaller stage user_system --config /root/installer/config.yaml",
+        "/root/installer/archinstaller install --config /root/installer/config.yaml",
     )?;
 
     run!("chmod", "+x", "/mnt/root/continue_install.sh").run()?;
@@ -143,3 +767,386 @@ pub fn setup_reboot_user_system(config: &Config) -> Result<(), InstallError> {
 pub fn reboot(_: &Config) -> Result<(), InstallError> {
     run!("reboot", "-h", "now").desc("rebooting").run()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Encryption, LogicalVolume, Lvm};
+    use crate::run::{set_executor, Mock};
+    use std::rc::Rc;
+
+    #[test]
+    fn bootloader_installs_systemd_boot() {
+        let mock = Rc::new(Mock::default().with_path("/sys/firmware/efi"));
+        set_executor(mock.clone());
+
+        bootloader_in_chroot(&Config::default()).unwrap();
+
+        let actions = mock.actions();
+        assert!(actions.contains(&"bootctl --path=/boot install".to_string()));
+        assert!(actions
+            .iter()
+            .any(|a| a.starts_with("write /boot/loader/entries/arch-linux.conf:")));
+    }
+
+    #[test]
+    fn grub_is_installed_to_mbr_on_bios() {
+        let mut config = Config::default();
+        config.bootloader.kind = BootloaderKind::Grub;
+        config.bootloader.firmware = Firmware::Bios;
+        let mock = Rc::new(Mock::default().respond("blkid -s UUID", "5e7d"));
+        set_executor(mock.clone());
+
+        bootloader_in_chroot(&config).unwrap();
+
+        let actions = mock.actions();
+        assert!(actions.contains(&"grub-install --target=i386-pc /dev/sdX".to_string()));
+        assert!(actions.contains(
+            &"sed -i -e 's|^GRUB_CMDLINE_LINUX=.*|GRUB_CMDLINE_LINUX=\"\"|' -e 's|^GRUB_TIMEOUT=.*|GRUB_TIMEOUT=3|' /etc/default/grub"
+                .to_string()
+        ));
+        assert_eq!(
+            actions.last().unwrap(),
+            "grub-mkconfig -o /boot/grub/grub.cfg"
+        );
+    }
+
+    #[test]
+    fn grub_is_installed_to_esp_on_uefi() {
+        let mut config = Config::default();
+        config.bootloader.kind = BootloaderKind::Grub;
+        config.installer.encryption = Some(Encryption {
+            name: "cryptroot".to_string(),
+            initramfs: Initramfs::Busybox,
+        });
+        config.installer.system_filesystem = Some(Filesystem::Btrfs);
+        config.bootloader.kernel_params = vec!["rootflags=compress=zstd".to_string()];
+        let mock = Rc::new(
+            Mock::default()
+                .with_path("/sys/firmware/efi")
+                .respond("blkid -s UUID -o value /dev/sdXn", "1234-abcd"),
+        );
+        set_executor(mock.clone());
+
+        bootloader_in_chroot(&config).unwrap();
+
+        let actions = mock.actions();
+        assert!(actions.contains(
+            &"grub-install --target=x86_64-efi --efi-directory=/boot --bootloader-id=GRUB"
+                .to_string()
+        ));
+        assert!(actions.contains(
+            &"sed -i -e 's|^GRUB_CMDLINE_LINUX=.*|GRUB_CMDLINE_LINUX=\"cryptdevice=UUID=1234-abcd:cryptroot rootflags=subvol=@,compress=zstd\"|' -e 's|^GRUB_TIMEOUT=.*|GRUB_TIMEOUT=3|' /etc/default/grub"
+                .to_string()
+        ));
+    }
+
+    #[test]
+    fn grub_options_are_escaped() {
+        let mut config = Config::default();
+        config.bootloader.kind = BootloaderKind::Grub;
+        config.bootloader.kernel_params = vec![
+            r#"acpi_osi="!Windows 2015""#.to_string(),
+            r"foo=a&b|c\d".to_string(),
+        ];
+        let mock = Rc::new(Mock::default().with_path("/sys/firmware/efi"));
+        set_executor(mock.clone());
+
+        bootloader_in_chroot(&config).unwrap();
+
+        let cmdline = r#"GRUB_CMDLINE_LINUX="acpi_osi=\\"!Windows 2015\\" foo=a\&b\|c\\\\d"|"#;
+        assert!(mock.actions().iter().any(|a| a.contains(cmdline)));
+    }
+
+    #[test]
+    fn secure_boot_signs_unified_kernel_images() {
+        let mut config = Config::default();
+        config.bootloader.uki = true;
+        config.bootloader.secure_boot = true;
+        let mock = Rc::new(
+            Mock::default()
+                .with_path("/sys/firmware/efi")
+                .respond("sbctl status --json", r#"{"setup_mode": true}"#),
+        );
+        set_executor(mock.clone());
+
+        bootloader_in_chroot(&config).unwrap();
+
+        let actions = mock.actions();
+        let position = |prefix: &str| actions.iter().position(|a| a.starts_with(prefix)).unwrap();
+        assert!(position("sbctl sign -s -o") < position("bootctl --path=/boot install"));
+        assert!(actions
+            .iter()
+            .any(|a| a.starts_with("write /etc/mkinitcpio.d/linux.preset:")
+                && a.contains("default_uki=\"/boot/EFI/Linux/arch-linux.efi\"")));
+        assert!(position("write /boot/loader/loader.conf:") > position("mkinitcpio -P"));
+        assert!(actions.iter().any(|a| a.contains("default arch-linux.efi")));
+        assert!(!actions.iter().any(|a| a.contains("/boot/loader/entries/")));
+        assert!(actions.contains(&"sbctl sign -s /boot/EFI/Linux/arch-linux.efi".to_string()));
+        assert!(!actions.iter().any(|a| a.contains("/etc/pacman.d/hooks/")));
+        assert_eq!(actions.last().unwrap(), "sbctl enroll-keys --microsoft");
+    }
+
+    #[test]
+    fn every_kernel_gets_an_entry_and_a_fallback() {
+        let mut config = Config::default();
+        config.bootloader.kernels.push(Kernel {
+            name: "linux-lts".to_string(),
+            params: vec!["mitigations=auto,nosmt".to_string()],
+        });
+        config.bootloader.default_kernel = Some("linux-lts".to_string());
+        let mock = Rc::new(Mock::default().with_path("/sys/firmware/efi"));
+        set_executor(mock.clone());
+
+        download_base(&config).unwrap();
+        bootloader_in_chroot(&config).unwrap();
+
+        let actions = mock.actions();
+        assert!(actions[0].starts_with("pacstrap /mnt base linux-firmware linux linux-lts"));
+        let entries: Vec<&String> = actions
+            .iter()
+            .filter(|a| a.starts_with("write /boot/loader/entries/"))
+            .collect();
+        assert_eq!(entries.len(), 4);
+        let lts = entries
+            .iter()
+            .find(|a| a.starts_with("write /boot/loader/entries/arch-linux-lts-fallback.conf:"))
+            .unwrap();
+        assert!(lts.contains("initrd /initramfs-linux-lts-fallback.img"));
+        assert!(lts.contains(" mitigations=auto,nosmt\n"));
+        assert!(!entries[0].contains("mitigations"));
+        assert!(actions
+            .iter()
+            .any(|a| a.contains("default arch-linux-lts.conf")));
+    }
+
+    #[test]
+    fn configured_kernel_params_are_merged() {
+        let mut config = Config::default();
+        config.installer.system_filesystem = Some(Filesystem::Btrfs);
+        config.bootloader.kernel_params = ["quiet", "rootflags=compress=zstd", "mitigations=auto"]
+            .iter()
+            .map(|p| p.to_string())
+            .collect();
+        config.bootloader.kernels[0].params = vec!["mitigations=off".to_string()];
+        config.bootloader.timeout = 0;
+        config.bootloader.editor = true;
+        let mock = Rc::new(
+            Mock::default()
+                .with_path("/sys/firmware/efi")
+                .respond("blkid -s PARTUUID", "0a1b-02"),
+        );
+        set_executor(mock.clone());
+
+        assert_eq!(
+            kernel_options(&config).unwrap(),
+            "root=PARTUUID=0a1b-02 rw rootflags=subvol=@,compress=zstd quiet mitigations=auto"
+        );
+        assert_eq!(
+            kernel_cmdline(&config, &config.bootloader.kernels[0]).unwrap(),
+            "root=PARTUUID=0a1b-02 rw rootflags=subvol=@,compress=zstd quiet mitigations=off"
+        );
+
+        bootloader_in_chroot(&config).unwrap();
+        assert!(mock.actions().contains(
+            &"write /boot/loader/loader.conf:
+timeout 0
+console-mode keep
+default arch-linux.conf
+editor yes
+"
+            .to_string()
+        ));
+    }
+
+    #[test]
+    fn microcode_follows_cpu_vendor() {
+        let mock = Rc::new(
+            Mock::default()
+                .with_path("/sys/firmware/efi")
+                .respond("grep -m1 vendor_id", "vendor_id\t: AuthenticAMD"),
+        );
+        set_executor(mock.clone());
+
+        bootloader_in_chroot(&Config::default()).unwrap();
+
+        let actions = mock.actions();
+        let entry = actions
+            .iter()
+            .find(|a| a.starts_with("write /boot/loader/entries/arch-linux.conf:"))
+            .unwrap();
+        assert!(entry.contains("initrd /amd-ucode.img\ninitrd /initramfs-linux.img"));
+        assert!(!entry.contains("intel-ucode"));
+
+        let mut config = Config::default();
+        config.hardware.microcode = Microcode::Both;
+        let mock = Rc::new(Mock::default());
+        set_executor(mock.clone());
+
+        base_in_chroot(&config).unwrap();
+
+        assert!(mock
+            .actions()
+            .contains(&"pacman --noconfirm -S intel-ucode amd-ucode".to_string()));
+    }
+
+    #[test]
+    fn unified_images_keep_both_microcodes() {
+        let mut config = Config::default();
+        config.bootloader.uki = true;
+        let position = |hooks: &[&str], hook: &str| hooks.iter().position(|h| *h == hook);
+
+        let hooks = mkinitcpio_hooks(&config);
+        assert!(position(&hooks, "microcode") > position(&hooks, "autodetect"));
+
+        config.hardware.microcode = Microcode::Both;
+        let hooks = mkinitcpio_hooks(&config);
+        assert!(position(&hooks, "microcode") < position(&hooks, "autodetect"));
+    }
+
+    #[test]
+    fn system_settings_are_applied() {
+        let mut config = Config::default();
+        config.system.timezone = "Europe/Berlin".to_string();
+        config.system.locales = vec![
+            "de_DE.UTF-8 UTF-8".to_string(),
+            "en_US.UTF-8 UTF-8".to_string(),
+        ];
+        config.system.keymap = "de-latin1".to_string();
+        let mock = Rc::new(Mock::default());
+        set_executor(mock.clone());
+
+        base_in_chroot(&config).unwrap();
+
+        let actions = mock.actions();
+        assert!(actions
+            .contains(&"ln -sf /usr/share/zoneinfo/Europe/Berlin /etc/localtime".to_string()));
+        assert!(actions.contains(&"append to /etc/locale.gen:\nde_DE.UTF-8 UTF-8".to_string()));
+        assert!(actions.contains(&"write /etc/locale.conf:\nLANG=de_DE.UTF-8\n".to_string()));
+        assert!(actions.contains(&"write /etc/vconsole.conf:\nKEYMAP=de-latin1\n".to_string()));
+    }
+
+    #[test]
+    fn present_locales_are_not_appended_again() {
+        let mut config = Config::default();
+        config.system.locales = vec!["de_DE.UTF-8 UTF-8".to_string()];
+        let mock = Rc::new(Mock::default().respond("grep -xF", "de_DE.UTF-8 UTF-8"));
+        set_executor(mock.clone());
+
+        base_in_chroot(&config).unwrap();
+
+        assert!(!mock
+            .actions()
+            .iter()
+            .any(|action| action.starts_with("append to /etc/locale.gen")));
+    }
+
+    #[test]
+    fn encrypted_root_is_unlocked_by_uuid() {
+        let mut config = Config::default();
+        config.installer.encryption = Some(Encryption {
+            name: "cryptroot".to_string(),
+            initramfs: Initramfs::Busybox,
+        });
+        set_executor(Rc::new(
+            Mock::default()
+                .respond("blkid -s UUID -o value /dev/sdXn", "1234-abcd")
+                .respond("blkid -s UUID -o value /dev/mapper/cryptroot", "5e7d-0001"),
+        ));
+        assert_eq!(
+            kernel_options(&config).unwrap(),
+            "cryptdevice=UUID=1234-abcd:cryptroot root=UUID=5e7d-0001 rw"
+        );
+
+        config.installer.encryption.as_mut().unwrap().initramfs = Initramfs::Systemd;
+        assert_eq!(
+            kernel_options(&config).unwrap(),
+            "rd.luks.name=1234-abcd=cryptroot root=UUID=5e7d-0001 rw"
+        );
+        assert!(mkinitcpio_hooks(&config).contains(&"sd-encrypt"));
+    }
+
+    #[test]
+    fn logical_volumes_are_created_on_system_disk() {
+        let mut config = Config::default();
+        config.installer.lvm = Some(Lvm {
+            volume_group: "vg0".to_string(),
+            physical_volumes: Vec::new(),
+            volumes: vec![LogicalVolume {
+                name: "root".to_string(),
+                size: "100%FREE".to_string(),
+                filesystem: None,
+                mount_point: Some("/".to_string()),
+            }],
+        });
+        let mock = Rc::new(Mock::default().respond("blkid -s UUID -o value /dev/vg0/root", "5e7d"));
+        set_executor(mock.clone());
+
+        format_system(&config.installer).unwrap();
+        assert_eq!(
+            mock.actions(),
+            vec![
+                "pvcreate --yes /dev/sdXn",
+                "vgcreate vg0 /dev/sdXn",
+                "lvcreate --yes -l 100%FREE -n root vg0",
+                "mkfs.ext4 /dev/vg0/root",
+            ]
+        );
+        assert_eq!(kernel_options(&config).unwrap(), "root=UUID=5e7d rw");
+        assert!(mkinitcpio_hooks(&config).contains(&"lvm2"));
+    }
+
+    #[test]
+    fn hibernation_resumes_from_swap_file() {
+        let config = Config {
+            swap: Some(Swap::File {
+                size: "8G".to_string(),
+                path: "/swapfile".into(),
+                hibernate: true,
+            }),
+            ..Config::default()
+        };
+        let filefrag = "Filesystem type is: ef53
+File size of /swapfile is 8589934592 (2097152 blocks of 4096 bytes)
+ ext:     logical_offset:        physical_offset: length:   expected: flags:
+   0:        0..   30719:     34816..     65535:  30720:
+   1:    30720..   63487:     67584..    100351:  32768:      65536:";
+        set_executor(Rc::new(
+            Mock::default()
+                .respond("filefrag", filefrag)
+                .respond("blkid -s PARTUUID", "0a1b-02"),
+        ));
+
+        assert_eq!(
+            kernel_options(&config).unwrap(),
+            "root=PARTUUID=0a1b-02 rw resume=PARTUUID=0a1b-02 resume_offset=34816"
+        );
+        assert!(mkinitcpio_hooks(&config).contains(&"resume"));
+    }
+
+    #[test]
+    fn btrfs_root_is_mounted_from_subvolume() {
+        let mut config = Config::default();
+        config.installer.system_filesystem = Some(Filesystem::Btrfs);
+        let mock = Rc::new(Mock::default().respond("blkid -s PARTUUID", "0a1b-02"));
+        set_executor(mock.clone());
+
+        mount_all(&config.installer).unwrap();
+        assert_eq!(
+            kernel_options(&config).unwrap(),
+            "root=PARTUUID=0a1b-02 rw rootflags=subvol=@"
+        );
+
+        let actions = mock.actions();
+        assert!(actions.contains(&"btrfs subvolume create /mnt/@home".to_string()));
+        let root = actions
+            .iter()
+            .position(|a| a == "mount -o subvol=@,noatime,compress=zstd /dev/sdXn /mnt/")
+            .unwrap();
+        let home = actions
+            .iter()
+            .position(|a| a.starts_with("mount -o subvol=@home,"))
+            .unwrap();
+        assert!(root < home);
+    }
+}
@@ -1,8 +1,7 @@
 use crate::config::Config;
+use crate::utils::*;
 use crate::InstallError;
 use std::env;
-use std::fs::remove_file;
-use std::path::PathBuf;
 pub fn set_git_user(config: &Config) -> Result<(), InstallError> {
     run!(
         "git",
@@ -25,7 +24,7 @@ pub fn set_git_user(config: &Config) -> Result<(), InstallError> {
 }
 
 pub fn rust_packages(config: &Config) -> Result<(), InstallError> {
-    let script_path = format!("/home/{}/rustup.sh", config.user.name);
+    let script_path = config.user.home().join("rustup.sh");
     run!(
         "curl",
         "--proto",
@@ -45,7 +44,7 @@ pub fn rust_packages(config: &Config) -> Result<(), InstallError> {
         .desc("running rustup install script")
         .run()?;
 
-    remove_file(&script_path)?;
+    delete_file(&script_path)?;
     
     let path = match env::var_os("PATH") {
         Some(path) => path,
@@ -53,7 +52,7 @@ pub fn rust_packages(config: &Config) -> Result<(), InstallError> {
     };
 
     let mut paths = env::split_paths(&path).collect::<Vec<_>>();
-    paths.push(["/home", &config.user.name, ".cargo/bin"].iter().collect::<PathBuf>());
+    paths.push(config.user.home().join(".cargo/bin"));
     let new_path = env::join_paths(paths)?;
     env::set_var("PATH", &new_path);
 
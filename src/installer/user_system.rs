@@ -1,8 +1,9 @@
-use crate::config::Config;
+use crate::config::{Config, Gpu};
+use crate::detect;
+use crate::run::{dry_run, path_exists};
+use crate::state::handoff;
 use crate::utils::*;
 use crate::InstallError;
-use std::fs::{copy, create_dir_all, remove_file};
-use std::path::PathBuf;
 use std::thread::sleep;
 use std::time::Duration;
 
@@ -29,9 +30,13 @@ DHCP=ipv4",
         .desc("enabling resolved")
         .run()?;
 
+    if dry_run() {
+        return Ok(());
+    }
+
     for _ in 0..10 {
         note("waiting for network");
-        let out = run!("ip", "a").to_string()?;
+        let out = run!("ip", "a").read()?;
         if out.contains("state UP") {
             return Ok(());
         }
@@ -62,16 +67,21 @@ pub fn essential_packages(_: &Config) -> Result<(), InstallError> {
     .run()?;
     Ok(())
 }
-pub fn vga(_: &Config) -> Result<(), InstallError> {
-    if run!("lspci").to_string()?.contains("NVIDIA") {
-        run!("pacman", "--noconfirm", "-S", "nvidia", "nvidia-settings")
-            .desc("install Nvidia drivers")
-            .run()
-    } else {
-        run!("pacman", "--noconfirm", "-S", "xf86-video-vesa")
-            .desc("install generic VGA driver")
-            .run()
-    }
+pub fn vga(config: &Config) -> Result<(), InstallError> {
+    let gpu = match config.hardware.gpu {
+        Gpu::Auto => detect::gpu(&run!("lspci").read()?),
+        gpu => gpu,
+    };
+    let (packages, desc): (&[&str], _) = match gpu {
+        Gpu::Nvidia => (&["nvidia", "nvidia-settings"], "install Nvidia drivers"),
+        Gpu::Amd => (&["mesa", "vulkan-radeon"], "install AMD drivers"),
+        Gpu::Intel => (&["mesa", "vulkan-intel"], "install Intel drivers"),
+        Gpu::Vesa | Gpu::Auto => (&["xf86-video-vesa"], "install generic VGA driver"),
+    };
+    run!("pacman", "--noconfirm", "-S")
+        .args(packages)
+        .desc(desc)
+        .run()
 }
 pub fn audio(_: &Config) -> Result<(), InstallError> {
     run!(
@@ -136,21 +146,27 @@ pub fn desktop(_: &Config) -> Result<(), InstallError> {
 
     Ok(())
 }
+pub fn dotfiles_exist(config: &Config) -> Result<bool, InstallError> {
+    Ok(path_exists(config.user.home().join("dotfiles/HEAD")))
+}
 pub fn setup_dotfiles(config: &Config) -> Result<(), InstallError> {
-    run!(
-        "git",
-        "clone",
-        "--bare",
-        "https://github.com/rozaliev/dotfiles.git",
-        format!("/home/{}/dotfiles", config.user.name)
-    )
-    .desc("cloning dotfiles")
-    .run()?;
+    let repo = match &config.user.dotfiles {
+        Some(repo) => repo,
+        None => {
+            note("skipping dotfiles, no repository is set as dotfiles in [user]");
+            return Ok(());
+        }
+    };
+    let home = config.user.home();
+    let git_dir = home.join("dotfiles");
+    run!("git", "clone", "--bare", repo, &git_dir)
+        .desc("cloning dotfiles")
+        .run()?;
 
     run!(
         "git",
-        format!("--git-dir=/home/{}/dotfiles", config.user.name),
-        format!("--work-tree=/home/{}", config.user.name),
+        format!("--git-dir={}", git_dir.display()),
+        format!("--work-tree={}", home.display()),
         "checkout"
     )
     .desc("checking out dotfiles")
@@ -184,6 +200,13 @@ pub fn desktop_packages(_: &Config) -> Result<(), InstallError> {
 
     Ok(())
 }
+pub fn user_exists(config: &Config) -> Result<bool, InstallError> {
+    match run!("id", "-u", &config.user.name).read() {
+        Ok(uid) => Ok(!uid.is_empty()),
+        Err(InstallError::CommandFailed { .. }) => Ok(false),
+        Err(err) => Err(err),
+    }
+}
 pub fn add_user(config: &Config) -> Result<(), InstallError> {
     run!(
         "useradd",
@@ -212,8 +235,12 @@ pub fn cleanup_reboot_hook(_: &Config) -> Result<(), InstallError> {
     Ok(())
 }
 
+pub fn ssh_key_exists(config: &Config) -> Result<bool, InstallError> {
+    Ok(path_exists(config.user.home().join(".ssh/id_rsa")))
+}
+
 pub fn generate_ssh_keys(config: &Config) -> Result<(), InstallError> {
-    run!("mkdir", "-p", format!("/home/{}/.ssh", config.user.name))
+    run!("mkdir", "-p", config.user.home().join(".ssh"))
         .desc("ensure that .ssh exists")
         .run()?;
     run!(
@@ -225,7 +252,7 @@ pub fn generate_ssh_keys(config: &Config) -> Result<(), InstallError> {
         "-C",
         &config.user.email,
         "-f",
-        format!("/home/{}/.ssh/id_rsa", config.user.name),
+        config.user.home().join(".ssh/id_rsa"),
         "-N",
         "\"\"",
     )
@@ -253,43 +280,127 @@ pub fn firewall(_: &Config) -> Result<(), InstallError> {
     Ok(())
 }
 pub fn setup_reboot_post_install(config: &Config) -> Result<(), InstallError> {
-    create_dir_all(format!("/home/{}/installer", config.user.name))?;
-    copy(
-        &config.path,
-        &["/home", &config.user.name, "installer/config.yaml"]
-            .iter()
-            .collect::<PathBuf>(),
-    )?;
+    let home = config.user.home();
+    make_dir(home.join("installer"))?;
+    copy_file(&config.path, home.join("installer/config.yaml"))?;
 
-    copy(
-        &std::env::current_exe()?,
-        &["/home", &config.user.name, "installer/archinstaller"]
-            .iter()
-            .collect::<PathBuf>(),
-    )?;
+    copy_file(std::env::current_exe()?, home.join("installer/archinstaller"))?;
+    handoff(config, home.join("installer/state.toml"), "post_install")?;
+    run!("cp", "-rT", config.log_dir(), home.join("installer/logs"))
+        .desc("copying session logs")
+        .run()?;
     set_file(
-        format!("/home/{}/continue_install.sh", config.user.name),
+        home.join("continue_install.sh"),
         "#!/bin/bash
-~/installer/archinstaller stage post_install --config ~/installer/config.yaml",
+~/installer/archinstaller install --config ~/installer/config.yaml",
     )?;
 
-    run!(
-        "chown",
-        "-R",
-        format!("{}:users", config.user.name),
-        "/home/erz"
-    )
-    .desc("fix permissions")
-    .run()?;
-    run!(
-        "chmod",
-        "+x",
-        &["/home", &config.user.name, "continue_install.sh"]
-            .iter()
-            .collect::<PathBuf>()
-    )
-    .desc("make installer runnable")
-    .run()?;
+    run!("chown", "-R", format!("{}:users", config.user.name), &home)
+        .desc("fix permissions")
+        .run()?;
+    run!("chmod", "+x", home.join("continue_install.sh"))
+        .desc("make installer runnable")
+        .run()?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::run::{set_executor, Mock};
+    use crate::tasks::TASKS;
+    use std::rc::Rc;
+
+    #[test]
+    fn vga_picks_nvidia_driver() {
+        let mock = Rc::new(Mock::default().respond(
+            "lspci",
+            "01:00.0 VGA compatible controller: NVIDIA Corporation GP104",
+        ));
+        set_executor(mock.clone());
+
+        vga(&Config::default()).unwrap();
+
+        assert!(mock
+            .actions()
+            .contains(&"pacman --noconfirm -S nvidia nvidia-settings".to_string()));
+    }
+
+    #[test]
+    fn vga_installs_driver_of_detected_gpu() {
+        for (lspci, packages) in &[
+            (
+                "03:00.0 VGA compatible controller: AMD/ATI Navi 23",
+                "mesa vulkan-radeon",
+            ),
+            (
+                "00:02.0 VGA compatible controller: Intel Corporation UHD Graphics 620",
+                "mesa vulkan-intel",
+            ),
+        ] {
+            let mut config = Config::default();
+            config.hardware.gpu = detect::gpu(lspci);
+            let mock = Rc::new(Mock::default());
+            set_executor(mock.clone());
+
+            vga(&config).unwrap();
+
+            assert_eq!(
+                mock.actions(),
+                vec![format!("pacman --noconfirm -S {}", packages)]
+            );
+        }
+    }
+
+    #[test]
+    fn vga_falls_back_to_vesa() {
+        let mock = Rc::new(
+            Mock::default().respond("lspci", "00:02.0 VGA compatible controller: Matrox G200"),
+        );
+        set_executor(mock.clone());
+
+        vga(&Config::default()).unwrap();
+
+        assert!(mock
+            .actions()
+            .contains(&"pacman --noconfirm -S xf86-video-vesa".to_string()));
+    }
+
+    #[test]
+    fn dotfiles_are_skipped_without_repository() {
+        let mock = Rc::new(Mock::default());
+        set_executor(mock.clone());
+
+        setup_dotfiles(&Config::default()).unwrap();
+
+        assert!(mock.actions().is_empty());
+    }
+
+    #[test]
+    fn existing_user_is_not_added_again() {
+        let config = Config::default();
+        let mock = Rc::new(Mock::default().respond("id -u", "1000"));
+        set_executor(mock.clone());
+
+        TASKS["add_user"].run(&config).unwrap();
+
+        assert_eq!(mock.actions(), vec![format!("id -u {}", config.user.name)]);
+    }
+
+    #[test]
+    fn ssh_key_is_generated_once() {
+        let config = Config::default();
+        let key = config.user.home().join(".ssh/id_rsa");
+
+        let mock = Rc::new(Mock::default());
+        set_executor(mock.clone());
+        TASKS["generate_ssh_keys"].run(&config).unwrap();
+        assert!(mock.actions().iter().any(|a| a.starts_with("ssh-keygen")));
+
+        let mock = Rc::new(Mock::default().with_path(key));
+        set_executor(mock.clone());
+        TASKS["generate_ssh_keys"].run(&config).unwrap();
+        assert!(mock.actions().is_empty());
+    }
+}
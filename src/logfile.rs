@@ -0,0 +1,71 @@
+use crate::InstallError;
+use lazy_static::lazy_static;
+use std::fs::{create_dir_all, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+lazy_static! {
+    static ref LOG: Mutex<Option<File>> = Mutex::new(None);
+}
+
+/// Starts teeing all output of this run into a new timestamped file in `dir`.
+pub fn start(dir: &Path) -> Result<PathBuf, InstallError> {
+    create_dir_all(dir)?;
+    let path = dir.join(format!(
+        "session-{}.log",
+        chrono::Local::now().format("%Y-%m-%d_%H-%M-%S")
+    ));
+    let file = File::create(&path)?;
+    *LOG.lock().unwrap() = Some(file);
+    Ok(path)
+}
+
+/// Appends a line to the session log, if there is one.
+pub fn write(line: &str) {
+    if let Some(file) = LOG.lock().unwrap().as_mut() {
+        // losing a log line is not worth failing the installation
+        let _ = writeln!(
+            file,
+            "[{}] {}",
+            chrono::Local::now().format("%H:%M:%S"),
+            strip_ansi(line)
+        );
+    }
+}
+
+pub fn section(title: &str) {
+    write(&format!("===== {} =====", title));
+}
+
+/// Removes terminal escape sequences such as colours from `s`.
+pub fn strip_ansi(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('[') => {
+                // CSI sequence, ends with a byte in range @..~
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            Some(']') => {
+                // OSC sequence, ends with BEL or ST
+                while let Some(c) = chars.next() {
+                    if c == '\x07' || (c == '\x1b' && chars.next() == Some('\\')) {
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    out
+}
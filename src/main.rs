@@ -1,6 +1,8 @@
-use crate::config::load_config;
+use crate::config::{check_config, read_config, Config};
+use crate::state::State;
 use crate::utils::*;
 use std::path::PathBuf;
+use std::rc::Rc;
 use structopt::StructOpt;
 use thiserror::Error;
 
@@ -8,9 +10,15 @@ use thiserror::Error;
 mod run;
 
 mod config;
+mod detect;
+mod disk;
 mod installer;
+mod logfile;
+mod safety;
+mod state;
 mod tasks;
 mod utils;
+mod validate;
 
 #[derive(Debug, Error)]
 pub enum InstallError {
@@ -36,9 +44,17 @@ pub enum InstallError {
     NoNetwork,
 
     #[error("cant join paths {0}")]
-    JoinPathError(#[from] std::env::JoinPathsError)
+    JoinPathError(#[from] std::env::JoinPathsError),
 
+    #[error("refusing to destroy disks")]
+    UnsafeDisks(Vec<String>),
 
+    #[error("command `{command}` failed with {status}")]
+    CommandFailed {
+        command: String,
+        status: std::process::ExitStatus,
+        tail: Vec<String>,
+    },
 }
 
 #[derive(StructOpt, Debug)]
@@ -47,7 +63,21 @@ pub enum InstallError {
     about = "Well, installs ArchLinux, i guess...",
     author
 )]
-enum Opt {
+struct Opt {
+    /// Print commands and file changes instead of executing them
+    #[structopt(long, global = true)]
+    dry_run: bool,
+
+    /// Allow destroying disks that still contain filesystems
+    #[structopt(long, global = true)]
+    wipe: bool,
+
+    #[structopt(subcommand)]
+    cmd: Command,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
     Install {
         #[structopt(short, long)]
         config: PathBuf,
@@ -65,63 +95,101 @@ enum Opt {
         config: PathBuf,
         name: String,
     },
-    ListTasks,
+    /// Check the config and report every problem found in it. Exit status
+    /// is a sum of: 1 - config unreadable, 2 - stages and tasks,
+    /// 4 - devices, 8 - user identity, 16 - timezone and locales,
+    /// 32 - bootloader.
+    Validate {
+        #[structopt(short, long)]
+        config: PathBuf,
+    },
+    ListTasks {
+        /// Print the task list as JSON
+        #[structopt(long)]
+        json: bool,
+    },
     ExampleConfig,
+    /// Inspect this machine and print a config suggested for it
+    Detect,
 }
 
 fn main() -> Result<(), std::io::Error> {
     let opt = Opt::from_args();
+    if opt.dry_run {
+        run::set_executor(Rc::new(run::DryRun));
+    }
+    safety::set_wipe(opt.wipe);
 
-    match opt {
-        Opt::Install { config } => {
-            let config = load_config(config)?;
-            let first_stage = &config.stages.first_stage;
-            let tasks = config.stages.map.get(first_stage).unwrap();
-            for task in tasks {
-                match task(&config) {
-                    Ok(_) => {}
-                    Err(InstallError::Decline) => {
-                        note("installation stopped by user");
-                        return Ok(());
-                    }
-                    Err(err) => {
-                        error(&format!("failed: {}", err));
-                        std::process::exit(1);
-                    }
+    match opt.cmd {
+        Command::Install { config } => {
+            let config = read_config(config)?;
+            let mut state = load_state(&config);
+            let stage = state.stage.clone();
+            check_config(&config, stage == config.stages.first_stage);
+            start_log(&config);
+            let tasks = match config.stages.map.get(&stage) {
+                Some(tasks) => tasks,
+                None => {
+                    error(&format!("there is no stage {}", stage));
+                    std::process::exit(1);
                 }
+            };
+
+            let start = tasks
+                .iter()
+                .position(|task| !state.is_done(&stage, task))
+                .unwrap_or(tasks.len());
+            if start == tasks.len() {
+                note(&format!("stage {} is already completed", stage));
+                return Ok(());
+            }
+            if start > 0 {
+                note(&format!("resuming stage {} from task {}", stage, tasks[start]));
+            }
+            for skipped in &tasks[..start] {
+                note(&format!("skipping task {}", skipped));
             }
+            run_tasks(&config, &mut state, &stage, &tasks[start..]);
         }
-        Opt::Stage {
+        Command::Stage {
             name: stage,
-            config, ..
+            config,
+            from,
         } => {
-            let config = load_config(config)?;
+            let config = read_config(config)?;
+            check_config(&config, stage == config.stages.first_stage);
+            start_log(&config);
             if let Some(tasks) = config.stages.map.get(&stage) {
-                for task in tasks {
-                    match task(&config) {
-                        Ok(_) => {}
-                        Err(InstallError::Decline) => {
-                            note("installation stopped by user");
-                            return Ok(());
-                        }
-                        Err(err) => {
-                            error(&format!("failed: {}", err));
+                let start = match from {
+                    Some(from) => match tasks.iter().position(|task| *task == from) {
+                        Some(pos) => pos,
+                        None => {
+                            error(&format!("there is no task {} in stage {}", from, stage));
                             std::process::exit(1);
                         }
-                    }
+                    },
+                    None => 0,
+                };
+                for skipped in &tasks[..start] {
+                    note(&format!("skipping task {}", skipped));
                 }
+                let mut state = load_state(&config);
+                run_tasks(&config, &mut state, &stage, &tasks[start..]);
             } else {
                 error(&format!("there is no stage {}", stage));
                 std::process::exit(1);
             }
         }
-        Opt::Task { name: task, config } => {
-            let config = load_config(config)?;
+        Command::Task { name: task, config } => {
+            // no session log, tasks run inside the chroot whose directory
+            // is removed afterwards; the caller logs their output
+            let config = read_config(config)?;
+            check_config(&config, false);
             if let Some(task) = tasks::TASKS.get(&task) {
-                match task(&config) {
+                match task.run(&config) {
                     Ok(_) | Err(InstallError::Decline) => {}
                     Err(err) => {
-                        error(&format!("failed: {}", err));
+                        report(&err);
                         std::process::exit(1);
                     }
                 }
@@ -130,15 +198,136 @@ fn main() -> Result<(), std::io::Error> {
                 std::process::exit(1);
             }
         }
-        Opt::ListTasks => {
+        Command::Validate { config } => {
+            let config = match read_config(config) {
+                Ok(config) => config,
+                Err(err) => {
+                    error(&format!("can't read config: {}", err));
+                    std::process::exit(validate::Category::Unreadable as i32);
+                }
+            };
+            let problems = validate::validate(&config, true);
+            if problems.is_empty() {
+                note("config is valid");
+                return Ok(());
+            }
+            for problem in &problems {
+                error(&problem.message);
+            }
+            std::process::exit(validate::exit_code(&problems));
+        }
+        Command::ListTasks { json } => {
+            if json {
+                let tasks: Vec<_> = tasks::TASKS.iter().collect();
+                println!("{}", serde_json::to_string_pretty(&tasks).unwrap());
+                return Ok(());
+            }
+
             note("List of all tasks:");
-            for name in tasks::TASKS.keys() {
-                println!("{}", name);
+            println!(
+                "{:<26} {:<14} {:<12} {:<8} DESCRIPTION",
+                "NAME", "RUNS IN", "DESTRUCTIVE", "NETWORK"
+            );
+            for task in tasks::TASKS.iter() {
+                println!(
+                    "{:<26} {:<14} {:<12} {:<8} {}",
+                    task.name,
+                    task.runs_in,
+                    if task.destructive { "yes" } else { "" },
+                    if task.network { "yes" } else { "" },
+                    task.description
+                );
             }
         }
-        Opt::ExampleConfig => {
-            println!("{}", config::Config::default().to_string());
+        Command::ExampleConfig => {
+            println!("{}", config::Config::default());
+        }
+        Command::Detect => {
+            let detected = match detect::detect() {
+                Ok(detected) => detected,
+                Err(err) => {
+                    report(&err);
+                    std::process::exit(1);
+                }
+            };
+            for line in detected.summary() {
+                println!("# {}", line);
+            }
+            println!();
+            println!("{}", detected.suggest());
         }
     }
     Ok(())
 }
+
+fn start_log(config: &Config) {
+    if run::dry_run() {
+        return;
+    }
+    match logfile::start(&config.log_dir()) {
+        Ok(path) => note(&format!("logging to {}", path.display())),
+        Err(err) => error(&format!("failed to start session log: {}", err)),
+    }
+}
+
+fn load_state(config: &Config) -> State {
+    match State::load(config) {
+        Ok(state) => state,
+        Err(err) => {
+            error(&format!("failed to load install state: {}", err));
+            std::process::exit(1);
+        }
+    }
+}
+
+fn run_tasks(config: &Config, state: &mut State, stage: &str, tasks: &[String]) {
+    for name in tasks {
+        if let Err(err) = state.start(stage, name) {
+            error(&format!("failed to save install state: {}", err));
+            std::process::exit(1);
+        }
+        logfile::section(&format!("task {} started", name));
+        let result = tasks::TASKS[name].run(config);
+        if let Err(err) = state.finish(&result) {
+            error(&format!("failed to save install state: {}", err));
+        }
+        logfile::section(&format!(
+            "task {} {}",
+            name,
+            match &result {
+                Ok(_) => "done".to_string(),
+                Err(InstallError::Decline) => "declined".to_string(),
+                Err(err) => format!("failed: {}", err),
+            }
+        ));
+        match result {
+            Ok(_) => {}
+            Err(InstallError::Decline) => {
+                note("installation stopped by user");
+                return;
+            }
+            Err(err) => {
+                report(&err);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+fn report(err: &InstallError) {
+    error(&format!("failed: {}", err));
+    match err {
+        InstallError::CommandFailed { tail, .. } if !tail.is_empty() => {
+            eprintln!("last output of the command:");
+            for line in tail {
+                eprintln!("  | {}", line);
+            }
+        }
+        InstallError::UnsafeDisks(reasons) => {
+            for reason in reasons {
+                eprintln!("  - {}", reason);
+            }
+        }
+        _ => {}
+    }
+}
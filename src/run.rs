@@ -1,122 +1,462 @@
+use crate::logfile;
 use crate::utils::*;
 use crate::InstallError;
 use duct::Expression;
 use std::borrow::Cow;
-use std::fs::File;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
 use std::io::{BufRead, BufReader, Write};
 use std::path::Path;
+#[cfg(test)]
+use std::path::PathBuf;
+use std::process::ExitStatus;
+use std::rc::Rc;
 
 #[macro_export]
 macro_rules! run {
-    ( $($t:tt)* ) => {
+    ( $program:expr $(, $arg:expr )* $(,)? ) => {
         {
-            crate::run::Wrap(duct::cmd!($($t)*))
+            $crate::run::Wrap::new(vec![
+                Into::<std::ffi::OsString>::into($program),
+                $( Into::<std::ffi::OsString>::into($arg), )*
+            ])
         }
 
     };
 }
 
-pub struct Wrap(pub Expression);
+/// Something the installer does to the machine.
+pub enum Action<'a> {
+    Run {
+        argv: &'a [OsString],
+        stdin: Option<&'a [u8]>,
+    },
+    /// Like `Run` with `stdin`, but the input is never shown.
+    RunWithSecret {
+        argv: &'a [OsString],
+        secret: &'a [u8],
+    },
+    Read {
+        argv: &'a [OsString],
+    },
+    ToFile {
+        argv: &'a [OsString],
+        path: &'a Path,
+    },
+    SetFile {
+        path: &'a Path,
+        contents: &'a str,
+    },
+    AppendToFile {
+        path: &'a Path,
+        contents: &'a str,
+    },
+    CopyFile {
+        from: &'a Path,
+        to: &'a Path,
+    },
+    MakeDir(&'a Path),
+    RemoveDir(&'a Path),
+    DeleteFile(&'a Path),
+}
+
+/// Renders `argv` the way it would be typed into a shell.
+fn command_line(argv: &[OsString]) -> String {
+    argv.iter()
+        .map(|arg| {
+            let arg = arg.to_string_lossy();
+            if !arg.is_empty()
+                && arg
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_=/.,:+@%".contains(c))
+            {
+                arg.into_owned()
+            } else {
+                format!("'{}'", arg.replace('\'', "'\\''"))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl fmt::Display for Action<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Action::Run { argv, stdin: None } | Action::Read { argv } => {
+                write!(f, "{}", command_line(argv))
+            }
+            Action::Run {
+                argv,
+                stdin: Some(stdin),
+            } => write!(
+                f,
+                "{} <<< {}",
+                command_line(argv),
+                String::from_utf8_lossy(stdin)
+            ),
+            Action::RunWithSecret { argv, .. } => {
+                write!(f, "{} <<< (secret)", command_line(argv))
+            }
+            Action::ToFile { argv, path } => {
+                write!(f, "{} > {}", command_line(argv), path.display())
+            }
+            Action::SetFile { path, contents } => {
+                write!(f, "write {}:\n{}", path.display(), contents)
+            }
+            Action::AppendToFile { path, contents } => {
+                write!(f, "append to {}:\n{}", path.display(), contents)
+            }
+            Action::CopyFile { from, to } => write!(f, "cp {} {}", from.display(), to.display()),
+            Action::MakeDir(path) => write!(f, "mkdir -p {}", path.display()),
+            Action::RemoveDir(path) => write!(f, "rm -r {}", path.display()),
+            Action::DeleteFile(path) => write!(f, "rm {}", path.display()),
+        }
+    }
+}
+
+/// Backend that carries out actions, returning the output of `Action::Read`.
+pub trait Executor {
+    fn execute(&self, action: &Action) -> Result<String, InstallError>;
+
+    fn exists(&self, _path: &Path) -> bool {
+        false
+    }
+
+    /// Whether actions really change the machine.
+    fn is_real(&self) -> bool {
+        false
+    }
+}
+
+thread_local! {
+    static EXECUTOR: RefCell<Rc<dyn Executor>> = RefCell::new(Rc::new(Duct));
+}
+
+pub fn set_executor(executor: Rc<dyn Executor>) {
+    EXECUTOR.with(|e| *e.borrow_mut() = executor);
+}
+
+pub fn execute(action: &Action) -> Result<String, InstallError> {
+    let executor = EXECUTOR.with(|e| e.borrow().clone());
+    executor.execute(action)
+}
+
+pub fn path_exists(path: impl AsRef<Path>) -> bool {
+    let executor = EXECUTOR.with(|e| e.borrow().clone());
+    executor.exists(path.as_ref())
+}
+
+/// In dry-run mode (or under a test executor) nothing is really executed.
+pub fn dry_run() -> bool {
+    !EXECUTOR.with(|e| e.borrow().is_real())
+}
+
+/// Runs everything for real.
+pub struct Duct;
+
+/// Prints every action instead of executing it.
+pub struct DryRun;
+
+/// Keeps every action instead of executing it.
+#[cfg(test)]
+#[derive(Default)]
+pub struct Recorder {
+    actions: RefCell<Vec<String>>,
+}
+
+/// Records actions and answers queries with scripted output.
+#[cfg(test)]
+#[derive(Default)]
+pub struct Mock {
+    recorder: Recorder,
+    responses: Vec<(String, String)>,
+    paths: Vec<PathBuf>,
+}
+
+impl Executor for Duct {
+    fn execute(&self, action: &Action) -> Result<String, InstallError> {
+        match *action {
+            Action::Run { argv, stdin: None } => exec(argv, expression(argv)),
+            Action::Run {
+                argv,
+                stdin: Some(stdin),
+            } => exec(argv, expression(argv).stdin_bytes(stdin)),
+            Action::RunWithSecret { argv, secret } => {
+                exec(argv, expression(argv).stdin_bytes(secret))
+            }
+            Action::Read { argv } => return exec_read(argv, expression(argv)),
+            Action::ToFile { argv, path } => exec_to_file(argv, expression(argv), path),
+            Action::SetFile { path, contents } => {
+                note(&format!("setting file {:?} to {}", path, contents));
+
+                let mut file = File::create(path)?;
+
+                file.write_all(contents.as_bytes())?;
+
+                note("successfully set");
+                Ok(())
+            }
+            Action::AppendToFile { path, contents } => {
+                note(&format!("appending {} to {:?}", contents, path));
+
+                let mut file = OpenOptions::new().append(true).open(path)?;
+                file.write_all(b"\n")?;
+                file.write_all(contents.as_bytes())?;
+
+                note("successfully appended");
+                Ok(())
+            }
+            Action::CopyFile { from, to } => fs::copy(from, to).map(|_| ()).map_err(Into::into),
+            Action::MakeDir(path) => fs::create_dir_all(path).map_err(Into::into),
+            Action::RemoveDir(path) => fs::remove_dir_all(path).map_err(Into::into),
+            Action::DeleteFile(path) => fs::remove_file(path).map_err(Into::into),
+        }?;
+
+        Ok(String::new())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn is_real(&self) -> bool {
+        true
+    }
+}
+
+impl Executor for DryRun {
+    fn execute(&self, action: &Action) -> Result<String, InstallError> {
+        dry(&action.to_string());
+        Ok(String::new())
+    }
+}
+
+#[cfg(test)]
+impl Recorder {
+    pub fn actions(&self) -> Vec<String> {
+        self.actions.borrow().clone()
+    }
+}
+
+#[cfg(test)]
+impl Executor for Recorder {
+    fn execute(&self, action: &Action) -> Result<String, InstallError> {
+        self.actions.borrow_mut().push(action.to_string());
+        Ok(String::new())
+    }
+}
+
+#[cfg(test)]
+impl Mock {
+    /// Answers every query whose command line starts with `command`.
+    pub fn respond(mut self, command: &str, output: &str) -> Mock {
+        self.responses.push((command.to_owned(), output.to_owned()));
+        self
+    }
+
+    /// Pretends that `path` exists.
+    pub fn with_path(mut self, path: impl Into<PathBuf>) -> Mock {
+        self.paths.push(path.into());
+        self
+    }
+
+    pub fn actions(&self) -> Vec<String> {
+        self.recorder.actions()
+    }
+}
+
+#[cfg(test)]
+impl Executor for Mock {
+    fn execute(&self, action: &Action) -> Result<String, InstallError> {
+        self.recorder.execute(action)?;
+        if let Action::Read { argv } = action {
+            let line = command_line(argv);
+            if let Some((_, output)) = self
+                .responses
+                .iter()
+                .find(|(command, _)| line.starts_with(command.as_str()))
+            {
+                return Ok(output.clone());
+            }
+        }
+        Ok(String::new())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.paths.iter().any(|p| p == path)
+    }
+}
+
+pub struct Wrap {
+    argv: Vec<OsString>,
+}
 
 pub struct WrapWithDescription {
-    exp: Expression,
+    argv: Vec<OsString>,
     description: String,
 }
 
 impl Wrap {
+    pub fn new(argv: Vec<OsString>) -> Wrap {
+        Wrap { argv }
+    }
+
+    /// Appends arguments only known at runtime, like a computed package list.
+    pub fn args<I, S>(mut self, args: I) -> Wrap
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<OsString>,
+    {
+        self.argv.extend(args.into_iter().map(Into::into));
+        self
+    }
+
     pub fn desc<'a>(self, description: impl Into<Cow<'a, str>>) -> WrapWithDescription {
         WrapWithDescription {
-            exp: self.0,
+            argv: self.argv,
             description: description.into().into_owned(),
         }
     }
 
-    #[must_use]
     pub fn run(self) -> Result<(), InstallError> {
-        exec(self.0)
+        execute(&Action::Run {
+            argv: &self.argv,
+            stdin: None,
+        })?;
+        Ok(())
     }
 
-    #[must_use]
-    pub fn to_string(mut self) -> Result<String, InstallError> {
-        for (key, value) in std::env::vars() {
-            self.0 = self.0.env(key, value);
-        }
-        let output = self.0.stderr_to_stdout().read()?;
-
-        Ok(output)
+    /// Runs a query and returns its output; in dry-run mode the output is empty.
+    pub fn read(self) -> Result<String, InstallError> {
+        execute(&Action::Read { argv: &self.argv })
     }
 }
 
 impl WrapWithDescription {
-    #[must_use]
     pub fn run(self) -> Result<(), InstallError> {
         note(&format!("--- starting  --- {}", self.description));
-        exec(self.exp)?;
+        execute(&Action::Run {
+            argv: &self.argv,
+            stdin: None,
+        })?;
         note(&format!("--- done --- {}", self.description));
         Ok(())
     }
 
-    #[must_use]
     pub fn run_with_stdin(self, stdin: &[u8]) -> Result<(), InstallError> {
         note(&format!("--- starting  --- {}", self.description));
-        exec_with_stdin(self.exp, stdin)?;
+        execute(&Action::Run {
+            argv: &self.argv,
+            stdin: Some(stdin),
+        })?;
+        note(&format!("--- done --- {}", self.description));
+        Ok(())
+    }
+
+    pub fn run_with_secret(self, secret: &str) -> Result<(), InstallError> {
+        note(&format!("--- starting  --- {}", self.description));
+        execute(&Action::RunWithSecret {
+            argv: &self.argv,
+            secret: secret.as_bytes(),
+        })?;
         note(&format!("--- done --- {}", self.description));
         Ok(())
     }
 
-    #[must_use]
+    #[allow(clippy::wrong_self_convention)]
     pub fn to_file(self, path: impl AsRef<Path>) -> Result<(), InstallError> {
         note(&format!("--- starting  --- {}", self.description));
         note(&format!(
             "will save the result to file: {:?}",
             path.as_ref()
         ));
-        exec_to_file(self.exp, path)?;
+        execute(&Action::ToFile {
+            argv: &self.argv,
+            path: path.as_ref(),
+        })?;
         note(&format!("--- done --- {}", self.description));
         Ok(())
     }
 }
 
-#[must_use]
-fn exec(mut exp: Expression) -> Result<(), InstallError> {
+fn expression(argv: &[OsString]) -> Expression {
+    let mut exp = duct::cmd(&argv[0], &argv[1..]);
     for (key, value) in std::env::vars() {
         exp = exp.env(key, value);
     }
+    exp
+}
 
-    let reader = exp.stderr_to_stdout().reader()?;
-    let mut lines = BufReader::new(reader).lines();
+/// Number of output lines kept for `InstallError::CommandFailed`.
+const TAIL_LINES: usize = 20;
 
-    while let Some(line) = lines.next() {
-        println!("{}", line?);
-    }
+fn tail_of(output: &[u8]) -> Vec<String> {
+    let output = String::from_utf8_lossy(output);
+    let lines: Vec<_> = output.lines().map(str::to_owned).collect();
+    lines[lines.len().saturating_sub(TAIL_LINES)..].to_vec()
+}
 
-    Ok(())
+/// Copies captured output into the session log, line by line.
+fn log_output(output: &[u8]) {
+    for line in String::from_utf8_lossy(output).lines() {
+        logfile::write(line);
+    }
 }
 
-#[must_use]
-fn exec_with_stdin(mut exp: Expression, stdin: &[u8]) -> Result<(), InstallError> {
-    for (key, value) in std::env::vars() {
-        exp = exp.env(key, value);
+fn check(argv: &[OsString], status: ExitStatus, tail: Vec<String>) -> Result<(), InstallError> {
+    if status.success() {
+        Ok(())
+    } else {
+        Err(InstallError::CommandFailed {
+            command: command_line(argv),
+            status,
+            tail,
+        })
     }
+}
 
-    let reader = exp.stdin_bytes(stdin).stderr_to_stdout().reader()?;
-    let mut lines = BufReader::new(reader).lines();
+fn exec(argv: &[OsString], exp: Expression) -> Result<(), InstallError> {
+    let reader = exp.stderr_to_stdout().unchecked().reader()?;
+    let mut tail = VecDeque::with_capacity(TAIL_LINES);
 
-    while let Some(line) = lines.next() {
-        println!("{}", line?);
+    for line in BufReader::new(&reader).lines() {
+        let line = line?;
+        println!("{}", line);
+        logfile::write(&line);
+        if tail.len() == TAIL_LINES {
+            tail.pop_front();
+        }
+        tail.push_back(line);
     }
 
-    Ok(())
+    let output = reader
+        .try_wait()?
+        .expect("process has to be finished after its output is closed");
+    check(argv, output.status, tail.into_iter().collect())
 }
-#[must_use]
-fn exec_to_file(mut exp: Expression, path: impl AsRef<Path>) -> Result<(), InstallError> {
-    for (key, value) in std::env::vars() {
-        exp = exp.env(key, value);
-    }
 
-    let output = exp.stdout_capture().run()?;
+fn exec_read(argv: &[OsString], exp: Expression) -> Result<String, InstallError> {
+    let output = exp.stderr_to_stdout().stdout_capture().unchecked().run()?;
+    log_output(&output.stdout);
+    check(argv, output.status, tail_of(&output.stdout))?;
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim_end().to_owned())
+}
+
+fn exec_to_file(
+    argv: &[OsString],
+    exp: Expression,
+    path: impl AsRef<Path>,
+) -> Result<(), InstallError> {
+    let output = exp.stdout_capture().stderr_capture().unchecked().run()?;
+    log_output(&output.stdout);
+    log_output(&output.stderr);
+    check(argv, output.status, tail_of(&output.stderr))?;
 
-    if output.stdout.len() == 0 {
+    if output.stdout.is_empty() {
         return Err(InstallError::EmptyResponse);
     }
 
@@ -0,0 +1,274 @@
+use crate::config::{Config, Installer, Swap};
+use crate::disk;
+use crate::run::dry_run;
+use crate::utils::*;
+use crate::InstallError;
+use std::cell::Cell;
+use std::fs::{read_dir, read_to_string};
+use std::path::{Path, PathBuf};
+
+thread_local! {
+    static WIPE: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Allows destroying devices that still contain filesystems.
+pub fn set_wipe(wipe: bool) {
+    WIPE.with(|w| w.set(wipe));
+}
+
+fn wipe() -> bool {
+    WIPE.with(Cell::get)
+}
+
+/// Devices `prepare` is going to overwrite.
+pub fn destroyed_devices(config: &Config) -> Vec<PathBuf> {
+    let installer = &config.installer;
+    let partitioned = !installer.partitions.is_empty();
+    let mut devices = Vec::new();
+    if partitioned {
+        devices.push(installer.install_disk.clone());
+    } else {
+        devices.push(installer.boot_disk.clone());
+        if installer.uses_system_disk() {
+            devices.push(installer.system_disk.clone());
+        }
+        if let Some(lvm) = &installer.lvm {
+            devices.extend(lvm.physical_volumes.iter().cloned());
+        }
+    }
+    if let Some(Swap::Partition { device, .. }) = &config.swap {
+        // partitions and logical volumes are created by `prepare` itself
+        if !partitioned && installer.lvm.is_none() {
+            devices.push(device.clone());
+        }
+    }
+    devices.retain(|d| !d.as_os_str().is_empty());
+    devices.sort();
+    devices.dedup();
+    devices
+}
+
+/// Refuses to go on if a device `prepare` overwrites is in use, is the live
+/// boot medium, still contains filesystems (unless `--wipe` was given) or
+/// isn't the disk the config expects; otherwise asks to destroy them.
+pub fn check(config: &Config) -> Result<(), InstallError> {
+    let devices = destroyed_devices(config);
+    let mounted = disk::mounted_devices();
+    let boot_medium = boot_medium();
+    let mut reasons = Vec::new();
+    let mut wiped = Vec::new();
+
+    for device in &devices {
+        let name = disk::block_name(device);
+        for m in &mounted {
+            let m_name = disk::block_name(m);
+            if m_name == name || disk::is_partition_of(&m_name, &name) {
+                reasons.push(format!(
+                    "{} is in use, {} is mounted",
+                    device.display(),
+                    m.display()
+                ));
+            }
+        }
+        for holder in holders(&name) {
+            reasons.push(format!("{} is in use by {}", device.display(), holder));
+        }
+        if boot_medium.as_deref().map(disk_of) == Some(disk_of(&name)) {
+            reasons.push(format!("{} is on the live boot medium", device.display()));
+        }
+
+        let filesystems = filesystems(device)?;
+        if filesystems.is_empty() {
+            continue;
+        }
+        if wipe() {
+            wiped.push(device);
+        } else {
+            reasons.push(format!(
+                "{} contains {}, pass --wipe to destroy it",
+                device.display(),
+                filesystems.join(", ")
+            ));
+        }
+    }
+    if !dry_run() {
+        check_expected(&config.installer, &mut reasons)?;
+    }
+
+    if !reasons.is_empty() {
+        if !dry_run() {
+            return Err(InstallError::UnsafeDisks(reasons));
+        }
+        for reason in &reasons {
+            dry(&format!("would refuse: {}", reason));
+        }
+    }
+
+    run!(
+        "lsblk",
+        "-o",
+        "NAME,SIZE,TYPE,FSTYPE,LABEL,MOUNTPOINTS,MODEL"
+    )
+    .args(&devices)
+    .desc("devices that will be destroyed")
+    .run()?;
+    confirm("All data on these devices will be lost, continue?")?;
+
+    for device in wiped {
+        run!("wipefs", "--all", device)
+            .desc(format!("wiping {}", device.display()))
+            .run()?;
+    }
+    Ok(())
+}
+
+/// Compares `install_disk` with `expected_model` and `expected_size`.
+fn check_expected(installer: &Installer, reasons: &mut Vec<String>) -> Result<(), InstallError> {
+    let disk = &installer.install_disk;
+    if let Some(expected) = &installer.expected_model {
+        let model = run!("lsblk", "-dno", "MODEL", disk).read()?;
+        if model.trim() != expected.trim() {
+            reasons.push(format!(
+                "{} is {:?}, not the expected {:?}",
+                disk.display(),
+                model.trim(),
+                expected
+            ));
+        }
+    }
+    if let Some(expected) = &installer.expected_size {
+        let size: u64 = run!("lsblk", "-bdno", "SIZE", disk)
+            .read()?
+            .trim()
+            .parse()
+            .unwrap_or(0);
+        let expected_bytes = parse_size(expected).unwrap_or(0);
+        // sizes in config are rounded
+        if size.abs_diff(expected_bytes) > expected_bytes / 100 {
+            reasons.push(format!(
+                "{} has {} bytes, not the expected {}",
+                disk.display(),
+                size,
+                expected
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Bytes in sizes like `512G`, `512GiB`, `500GB` or `1000000`.
+pub fn parse_size(size: &str) -> Option<u64> {
+    let size = size.trim();
+    let split = size
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let number: f64 = number.parse().ok()?;
+    let multiplier = match unit.trim() {
+        "" | "B" => 1u64,
+        "K" | "KiB" => 1 << 10,
+        "M" | "MiB" => 1 << 20,
+        "G" | "GiB" => 1 << 30,
+        "T" | "TiB" => 1 << 40,
+        "KB" => 1_000,
+        "MB" => 1_000_000,
+        "GB" => 1_000_000_000,
+        "TB" => 1_000_000_000_000,
+        _ => return None,
+    };
+    Some((number * multiplier as f64) as u64)
+}
+
+/// Filesystems and other signatures on `device` and its partitions.
+fn filesystems(device: &Path) -> Result<Vec<String>, InstallError> {
+    let mut found: Vec<String> = Vec::new();
+    for fstype in run!("lsblk", "-no", "FSTYPE", device).read()?.lines() {
+        let fstype = fstype.trim();
+        if !fstype.is_empty() && !found.iter().any(|f| f == fstype) {
+            found.push(fstype.to_string());
+        }
+    }
+    Ok(found)
+}
+
+/// Devices like dm-crypt or LVM built on `name` or its partitions.
+fn holders(name: &str) -> Vec<String> {
+    let sys = Path::new("/sys/class/block").join(name);
+    let mut dirs = vec![sys.join("holders")];
+    if let Ok(entries) = read_dir(&sys) {
+        for entry in entries.flatten() {
+            if entry.path().join("partition").exists() {
+                dirs.push(entry.path().join("holders"));
+            }
+        }
+    }
+    dirs.iter()
+        .filter_map(|dir| read_dir(dir).ok())
+        .flat_map(|entries| entries.flatten())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .collect()
+}
+
+/// Kernel name of the device the live ISO was booted from.
+pub fn boot_medium() -> Option<String> {
+    let cmdline = read_to_string("/proc/cmdline").ok()?;
+    let spec = cmdline.split_whitespace().find_map(|option| {
+        if let Some(label) = option.strip_prefix("archisolabel=") {
+            Some(format!("LABEL={}", label))
+        } else if let Some(uuid) = option.strip_prefix("archisosearchuuid=") {
+            Some(format!("UUID={}", uuid))
+        } else {
+            option.strip_prefix("img_dev=").map(str::to_string)
+        }
+    })?;
+    let device = disk::resolve(Path::new(&spec));
+    if device == Path::new(&spec) && spec.contains('=') {
+        return None;
+    }
+    Some(disk::block_name(&device))
+}
+
+/// Whole disk the block device `name` is on.
+pub fn disk_of(name: &str) -> String {
+    let sys = Path::new("/sys/class/block").join(name);
+    if sys.join("partition").exists() {
+        if let Some(disk) = sys.canonicalize().ok().and_then(|path| {
+            path.parent()
+                .and_then(Path::file_name)
+                .map(|n| n.to_string_lossy().into_owned())
+        }) {
+            return disk;
+        }
+    }
+    name.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_use_binary_and_decimal_units() {
+        assert_eq!(parse_size("512G"), Some(512 << 30));
+        assert_eq!(parse_size("512GiB"), Some(512 << 30));
+        assert_eq!(parse_size("500GB"), Some(500_000_000_000));
+        assert_eq!(parse_size("1.5T"), Some(3 << 39));
+        assert_eq!(parse_size("4096"), Some(4096));
+        assert_eq!(parse_size("lots"), None);
+    }
+
+    #[test]
+    fn only_install_disk_is_destroyed_when_partitioning() {
+        let mut config = Config::default();
+        assert_eq!(destroyed_devices(&config), vec![PathBuf::from("/dev/sdXn")]);
+
+        config.installer.partitions.push(crate::config::Partition {
+            size: None,
+            type_guid: None,
+            label: None,
+            filesystem: None,
+            mount_point: Some("/".to_string()),
+        });
+        assert_eq!(destroyed_devices(&config), vec![PathBuf::from("/dev/sdX")]);
+    }
+}
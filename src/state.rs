@@ -0,0 +1,144 @@
+use crate::config::Config;
+use crate::run::dry_run;
+use crate::utils::*;
+use crate::InstallError;
+use serde::{Deserialize, Serialize};
+use std::fs::{read_to_string, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Running,
+    Done,
+    Failed,
+    Declined,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TaskRecord {
+    pub stage: String,
+    pub name: String,
+    pub status: Status,
+    pub started: String,
+    pub finished: Option<String>,
+    pub error: Option<String>,
+}
+
+/// Journal of an installation, kept next to the config file so it travels
+/// with it from the live ISO to the installed system.
+#[derive(Serialize, Deserialize)]
+pub struct State {
+    pub stage: String,
+    #[serde(default)]
+    pub tasks: Vec<TaskRecord>,
+    #[serde(default, skip)]
+    path: PathBuf,
+}
+
+pub fn state_path(config_path: &Path) -> PathBuf {
+    config_path.with_file_name("state.toml")
+}
+
+fn now() -> String {
+    chrono::Local::now().to_rfc3339()
+}
+
+impl State {
+    /// Loads the journal that belongs to `config`, or starts a new one at
+    /// the first stage.
+    pub fn load(config: &Config) -> Result<State, InstallError> {
+        let path = state_path(&config.path);
+        if !path.exists() {
+            return Ok(State {
+                stage: config.stages.first_stage.clone(),
+                tasks: Vec::new(),
+                path,
+            });
+        }
+
+        let mut state: State = toml::from_str(&read_to_string(&path)?)
+            .map_err(|_| InstallError::InvalidFile(path.display().to_string()))?;
+        state.path = path;
+        Ok(state)
+    }
+
+    pub fn save(&self) -> Result<(), InstallError> {
+        if dry_run() {
+            return Ok(());
+        }
+        self.save_to(&self.path)
+    }
+
+    fn save_to(&self, path: impl AsRef<Path>) -> Result<(), InstallError> {
+        if dry_run() {
+            dry(&format!(
+                "write journal for stage {} to {}",
+                self.stage,
+                path.as_ref().display()
+            ));
+            return Ok(());
+        }
+        let s = toml::to_string(self).expect("state has to be serializable");
+        let mut file = File::create(path)?;
+        file.write_all(s.as_bytes())?;
+        Ok(())
+    }
+
+    pub fn is_done(&self, stage: &str, task: &str) -> bool {
+        self.tasks
+            .iter()
+            .rev()
+            .find(|r| r.stage == stage && r.name == task)
+            .is_some_and(|r| r.status == Status::Done)
+    }
+
+    pub fn start(&mut self, stage: &str, task: &str) -> Result<(), InstallError> {
+        self.stage = stage.to_owned();
+        self.tasks.push(TaskRecord {
+            stage: stage.to_owned(),
+            name: task.to_owned(),
+            status: Status::Running,
+            started: now(),
+            finished: None,
+            error: None,
+        });
+        self.save()
+    }
+
+    pub fn finish(&mut self, result: &Result<(), InstallError>) -> Result<(), InstallError> {
+        let record = self
+            .tasks
+            .last_mut()
+            .expect("finish has to be called after start");
+        record.finished = Some(now());
+        record.status = match result {
+            Ok(_) => Status::Done,
+            Err(InstallError::Decline) => Status::Declined,
+            Err(err) => {
+                record.error = Some(err.to_string());
+                Status::Failed
+            }
+        };
+        self.save()
+    }
+}
+
+/// Writes a copy of the current journal to `dest`, switched to `next_stage`,
+/// so that `install` picks up there after the reboot.
+pub fn handoff(
+    config: &Config,
+    dest: impl AsRef<Path>,
+    next_stage: &str,
+) -> Result<(), InstallError> {
+    let mut state = State::load(config)?;
+    state.stage = next_stage.to_owned();
+
+    note(&format!(
+        "installation will continue with stage {} from {:?}",
+        next_stage,
+        dest.as_ref()
+    ));
+    state.save_to(dest)
+}
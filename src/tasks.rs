@@ -1,54 +1,262 @@
 use crate::config::Config;
 use crate::installer;
+use crate::utils::*;
 use crate::InstallError;
 use lazy_static::lazy_static;
-use std::collections::HashMap;
+use serde::Serialize;
+use std::fmt;
+use std::ops::Index;
 
 macro_rules! t {
-    ($hm:expr, $i:ident) => {
-        $hm.insert(stringify!($i).to_string(), installer::$i as Task);
+    (
+        $r:expr,
+        $i:ident $(unless $check:ident)? $(after [$($dep:ident),+])? $(=> $next:ident)?,
+        $runs_in:ident,
+        $desc:expr
+        $(, $flag:ident)*
+    ) => {
+        #[allow(unused_mut)]
+        let mut info = TaskInfo {
+            name: stringify!($i),
+            description: $desc,
+            runs_in: RunsIn::$runs_in,
+            destructive: false,
+            network: false,
+            requires: &[$($(stringify!($dep)),+)?],
+            next_stage: None $( .or(Some(stringify!($next))) )?,
+            task: Box::new(FnTask {
+                apply: installer::$i,
+                check: None $( .or(Some(installer::$check as Check)) )?,
+            }),
+        };
+        $( info.$flag = true; )*
+        $r.push(info);
     };
 }
 
 lazy_static! {
-    pub static ref TASKS: HashMap<String, Task> = {
-        let mut m = HashMap::new();
+    pub static ref TASKS: Registry = {
+        let mut m = Vec::new();
         // base stage
-        t!(m, prepare);
-        t!(m, download_base);
-        t!(m, base);
-        t!(m, base_in_chroot);
-        t!(m, bootloader);
-        t!(m, bootloader_in_chroot);
-        t!(m, setup_reboot_user_system);
-        t!(m, reboot);
+        t!(m, prepare, LiveIso, "format and mount boot and system disks", destructive, network);
+        t!(m, download_base after [prepare], LiveIso, "pacstrap base system and kernels", network);
+        t!(m, base after [download_base], LiveIso, "generate fstab and configure the base system");
+        t!(m, base_in_chroot after [download_base], Chroot, "set timezone, locale, hostname and microcode", network);
+        t!(m, bootloader after [base], LiveIso, "install the bootloader");
+        t!(m, bootloader_in_chroot after [download_base], Chroot, "install systemd-boot or grub and write its config", network);
+        t!(m, setup_reboot_user_system after [bootloader] => user_system, LiveIso, "prepare installer to continue after reboot");
+        t!(m, reboot, Any, "reboot the machine");
 
 
         // user stage
-        t!(m, set_in_qemu_http_proxy);
-        t!(m, cleanup_reboot_hook);
-        t!(m, systemd_network);
-        t!(m, essential_packages);
-        t!(m, vga);
-        t!(m, audio);
-        t!(m, desktop);
-        t!(m, desktop_packages);
-        t!(m, setup_dotfiles);
-        t!(m, codecs);
-        t!(m, terminal_packages);
-        t!(m, add_user);
-        t!(m, generate_ssh_keys);
-        t!(m, disable_root_login);
-        t!(m, power_management);
-        t!(m, firewall);
-        t!(m, setup_reboot_post_install);
+        t!(m, set_in_qemu_http_proxy, Any, "use http proxy of the qemu host");
+        t!(m, cleanup_reboot_hook, System, "remove the continue-after-reboot hook");
+        t!(m, systemd_network, System, "enable DHCP with systemd-networkd", network);
+        t!(m, essential_packages, System, "install editor, git, ssh and build tools", network);
+        t!(m, vga, System, "install video driver for the detected GPU", network);
+        t!(m, audio, System, "install pulseaudio", network);
+        t!(m, desktop, System, "install xorg, lightdm and i3", network);
+        t!(m, desktop_packages, System, "install desktop applications and fonts", network);
+        t!(m, setup_dotfiles unless dotfiles_exist after [add_user, essential_packages], System, "check out dotfiles into the user's home", network);
+        t!(m, codecs, System, "install media codecs");
+        t!(m, terminal_packages, System, "install terminal, shell and cli tools", network);
+        t!(m, add_user unless user_exists after [terminal_packages], System, "create the user and allow sudo");
+        t!(m, generate_ssh_keys unless ssh_key_exists after [add_user, essential_packages], System, "generate ssh key for the user");
+        t!(m, disable_root_login, System, "lock the root password");
+        t!(m, power_management, System, "install power management tools", network);
+        t!(m, firewall, System, "install and enable nftables", network);
+        t!(m, setup_reboot_post_install after [add_user] => post_install, System, "prepare installer to continue as user");
 
         // post install
-        t!(m, set_git_user);
-        t!(m, rust_packages);
-        t!(m, vpn);
+        t!(m, set_git_user after [essential_packages], User, "set git user name and email");
+        t!(m, rust_packages after [add_user, essential_packages], User, "install rust toolchain and cargo tools", network);
+        t!(m, vpn, User, "set up vpn", network);
 
-        m
+        Registry { tasks: m }
     };
 }
-pub type Task = fn(&Config) -> Result<(), InstallError>;
+pub type Apply = fn(&Config) -> Result<(), InstallError>;
+pub type Check = fn(&Config) -> Result<bool, InstallError>;
+
+pub trait Task: Send + Sync {
+    /// Whether the task is already satisfied and can be skipped.
+    fn check(&self, _config: &Config) -> Result<bool, InstallError> {
+        Ok(false)
+    }
+
+    fn apply(&self, config: &Config) -> Result<(), InstallError>;
+}
+
+/// Task made of plain functions from the `installer` module.
+pub struct FnTask {
+    pub apply: Apply,
+    pub check: Option<Check>,
+}
+
+impl Task for FnTask {
+    fn check(&self, config: &Config) -> Result<bool, InstallError> {
+        match self.check {
+            Some(check) => check(config),
+            None => Ok(false),
+        }
+    }
+
+    fn apply(&self, config: &Config) -> Result<(), InstallError> {
+        (self.apply)(config)
+    }
+}
+
+/// Where a task is expected to run.
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum RunsIn {
+    LiveIso,
+    Chroot,
+    /// installed system, as root
+    System,
+    /// installed system, as the user
+    User,
+    Any,
+}
+
+impl fmt::Display for RunsIn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            RunsIn::LiveIso => "live ISO",
+            RunsIn::Chroot => "chroot",
+            RunsIn::System => "system (root)",
+            RunsIn::User => "user",
+            RunsIn::Any => "any",
+        })
+    }
+}
+
+#[derive(Serialize)]
+pub struct TaskInfo {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub runs_in: RunsIn,
+    pub destructive: bool,
+    pub network: bool,
+    /// tasks that have to run before this one
+    pub requires: &'static [&'static str],
+    /// stage the installation continues with after this task
+    pub next_stage: Option<&'static str>,
+    #[serde(skip)]
+    pub task: Box<dyn Task>,
+}
+
+impl TaskInfo {
+    /// Applies the task unless its check says it is already done.
+    pub fn run(&self, config: &Config) -> Result<(), InstallError> {
+        if self.task.check(config)? {
+            note(&format!("task {} is already done, skipping", self.name));
+            return Ok(());
+        }
+        self.task.apply(config)
+    }
+}
+
+/// All known tasks, in the order they were registered.
+pub struct Registry {
+    tasks: Vec<TaskInfo>,
+}
+
+impl Registry {
+    pub fn get(&self, name: &str) -> Option<&TaskInfo> {
+        self.tasks.iter().find(|t| t.name == name)
+    }
+
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &TaskInfo> {
+        self.tasks.iter()
+    }
+}
+
+impl Index<&str> for Registry {
+    type Output = TaskInfo;
+
+    fn index(&self, name: &str) -> &TaskInfo {
+        self.get(name)
+            .unwrap_or_else(|| panic!("there is no task {}", name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::run::{set_executor, Mock};
+    use std::rc::Rc;
+
+    #[test]
+    fn identity_comes_from_config() {
+        let mut config = Config::default();
+        config.user.name = "sentineluser".to_string();
+        config.user.full_name = "Sentinel Person".to_string();
+        config.user.email = "sentinel@example.org".to_string();
+        config.user.hostname = "sentinelhost".to_string();
+        config.user.dotfiles = Some("https://example.org/sentinel/dotfiles.git".to_string());
+
+        let user = &config.user;
+        let full_name = format!("'{}'", user.full_name);
+        let password = format!("{0}:{0}", user.name);
+        let dotfiles = user.dotfiles.as_deref().unwrap();
+        // text in front of a value from `[user]`, and the value it has to be
+        let slots = [
+            ("/home/", user.name.as_str()),
+            ("/usr/bin/fish ", &user.name),
+            ("chpasswd <<< ", &password),
+            ("/etc/sudoers:\n", &user.name),
+            ("chown -R ", &user.name),
+            ("user.email ", &user.email),
+            ("user.name ", &full_name),
+            ("-C ", &user.email),
+            ("/etc/hostname:\n", &user.hostname),
+            ("127.0.1.1\t", &user.hostname),
+            ("--bare ", dotfiles),
+        ];
+        let mut seen = vec![false; slots.len()];
+        let ends_value =
+            |rest: &str| rest.is_empty() || rest.starts_with(&[' ', '\t', '\n', '/', ':', '.'][..]);
+
+        // vpn is still `unimplemented!()` and would panic
+        for info in TASKS.iter().filter(|t| t.name != "vpn") {
+            let mock = Rc::new(Mock::default().with_path("/sys/firmware/efi"));
+            set_executor(mock.clone());
+            info.task.apply(&config).unwrap();
+
+            for action in mock.actions() {
+                for hardcoded in &["erz", "wpc", "rozaliev", "Evgeny"] {
+                    assert!(
+                        !action.contains(hardcoded),
+                        "task {} does '{}'",
+                        info.name,
+                        action
+                    );
+                }
+                for (i, (before, expected)) in slots.iter().enumerate() {
+                    for value in action.split(before).skip(1) {
+                        seen[i] = true;
+                        assert!(
+                            value.strip_prefix(expected).is_some_and(ends_value),
+                            "task {} does '{}' instead of using {}",
+                            info.name,
+                            action,
+                            expected
+                        );
+                    }
+                }
+            }
+        }
+        for ((before, _), seen) in slots.iter().zip(seen) {
+            assert!(
+                seen,
+                "no task does '{}...' anymore",
+                before.escape_default()
+            );
+        }
+    }
+}
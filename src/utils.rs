@@ -1,71 +1,90 @@
 use crate::config::Config;
+use crate::logfile;
+use crate::run::{dry_run, execute, Action};
 use crate::tasks::TASKS;
 use crate::InstallError;
 use ansi_term::Colour;
-use dialoguer::{Confirmation, Input};
-use std::fs::{copy, create_dir_all, remove_dir_all, File, OpenOptions};
-use std::io::{Read, Write};
+use dialoguer::{Confirmation, PasswordInput};
 use std::path::{Path, PathBuf};
 
-#[must_use]
-pub fn exists(path: Vec<&str>) -> Result<PathBuf, InstallError> {
-    let mut buf = PathBuf::new();
-    for p in path {
-        buf.push(p);
-    }
-    let p = buf.as_path();
-    if p.exists() && !p.is_dir() {
-        Ok(buf)
-    } else {
-        Err(InstallError::InvalidFile(
-            p.to_str().expect("path to str faield").to_string(),
-        ))
-    }
-}
-
-#[must_use]
 pub fn append_to_file(path: impl AsRef<Path>, s: &str) -> Result<(), InstallError> {
-    note(&format!("appending {} to {:?}", s, path.as_ref()));
-
-    let mut file = OpenOptions::new().append(true).open(path)?;
-    file.write(b"\n")?;
-    file.write_all(s.as_bytes())?;
-
-    note("successfully appended");
+    execute(&Action::AppendToFile {
+        path: path.as_ref(),
+        contents: s,
+    })?;
     Ok(())
 }
 
-#[must_use]
 pub fn set_file(path: impl AsRef<Path>, s: &str) -> Result<(), InstallError> {
-    note(&format!("setting file {:?} to {}", path.as_ref(), s));
+    execute(&Action::SetFile {
+        path: path.as_ref(),
+        contents: s,
+    })?;
+    Ok(())
+}
+
+pub fn copy_file(from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<(), InstallError> {
+    execute(&Action::CopyFile {
+        from: from.as_ref(),
+        to: to.as_ref(),
+    })?;
+    Ok(())
+}
 
-    let mut file = File::create(path)?;
+pub fn make_dir(path: impl AsRef<Path>) -> Result<(), InstallError> {
+    execute(&Action::MakeDir(path.as_ref()))?;
+    Ok(())
+}
 
-    file.write_all(s.as_bytes())?;
+pub fn remove_dir(path: impl AsRef<Path>) -> Result<(), InstallError> {
+    execute(&Action::RemoveDir(path.as_ref()))?;
+    Ok(())
+}
 
-    note("successfully set");
+pub fn delete_file(path: impl AsRef<Path>) -> Result<(), InstallError> {
+    execute(&Action::DeleteFile(path.as_ref()))?;
     Ok(())
 }
 
 pub fn confirm(s: &str) -> Result<(), InstallError> {
-    if Confirmation::new()
+    if dry_run() {
+        dry(&format!("confirm: {} yes", s));
+        return Ok(());
+    }
+    let confirmed = Confirmation::new()
         .with_text(&Colour::Green.bold().paint(s).to_string())
         .show_default(true)
-        .interact()?
-    {
+        .interact()?;
+    logfile::write(&format!("{} {}", s, if confirmed { "yes" } else { "no" }));
+    if confirmed {
         Ok(())
     } else {
         Err(InstallError::Decline)
     }
 }
 
-pub fn prompt(s: &str) -> Result<String, InstallError> {
-    let input: String = Input::new().with_prompt(s).interact()?;
+/// Asks for a secret twice; it is neither echoed nor logged.
+pub fn password(s: &str) -> Result<String, InstallError> {
+    if dry_run() {
+        dry(&format!("password: {} ********", s));
+        return Ok("dry-run".to_string());
+    }
+    let input = PasswordInput::new()
+        .with_prompt(s)
+        .with_confirmation("Repeat it", "they don't match, try again")
+        .interact()?;
+    logfile::write(&format!("{}: ********", s));
     Ok(input)
 }
 
 pub fn note(s: &str) {
     println!("{} {}", Colour::Yellow.bold().paint("NOTE:"), s);
+    logfile::write(&format!("NOTE: {}", s));
+}
+
+pub fn dry(s: &str) {
+    println!("{} {}", Colour::Purple.bold().paint("DRY RUN:"), s);
+    logfile::write(&format!("DRY RUN: {}", s));
 }
 
 pub fn error(s: &str) {
@@ -74,6 +93,7 @@ pub fn error(s: &str) {
         Colour::Red.bold().paint("Error:"),
         Colour::Cyan.paint(s)
     );
+    logfile::write(&format!("Error: {}", s));
 }
 
 pub fn with_chroot(config: &Config, task: &str) -> Result<(), InstallError> {
@@ -82,15 +102,15 @@ pub fn with_chroot(config: &Config, task: &str) -> Result<(), InstallError> {
     }
 
     let wd = "/mnt/_chroot_install";
-    create_dir_all(wd)?;
-    copy(
+    make_dir(wd)?;
+    copy_file(
         &config.path,
-        &[wd, "config.yaml"].iter().collect::<PathBuf>(),
+        [wd, "config.yaml"].iter().collect::<PathBuf>(),
     )?;
 
-    copy(
-        &std::env::current_exe()?,
-        &[wd, "archinstaller"].iter().collect::<PathBuf>(),
+    copy_file(
+        std::env::current_exe()?,
+        [wd, "archinstaller"].iter().collect::<PathBuf>(),
     )?;
 
     run!(
@@ -102,10 +122,15 @@ pub fn with_chroot(config: &Config, task: &str) -> Result<(), InstallError> {
         "--config",
         "/_chroot_install/config.yaml"
     )
-    .desc(&format!("chrooting for task {}", task))
+    .desc(format!("chrooting for task {}", task))
     .run()?;
 
-    remove_dir_all(wd)?;
+    if dry_run() {
+        // nothing was chrooted into, so show what the task would do there
+        TASKS[task].run(config)?;
+    }
+
+    remove_dir(wd)?;
 
     Ok(())
 }
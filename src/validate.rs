@@ -0,0 +1,602 @@
+use crate::config::{BootloaderKind, Config, Filesystem, Firmware, PartitionTable, Swap, KERNELS};
+use crate::disk;
+use crate::installer::param_name;
+use crate::run::{dry_run, path_exists};
+use crate::safety;
+use crate::tasks::TASKS;
+use std::fs::read_to_string;
+use std::path::Path;
+
+/// Kind of a config problem; `validate` exits with the bits of all kinds found.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Category {
+    Unreadable = 1,
+    Stages = 2,
+    Devices = 4,
+    Identity = 8,
+    System = 16,
+    Boot = 32,
+}
+
+pub struct Problem {
+    pub category: Category,
+    pub message: String,
+}
+
+impl Problem {
+    fn new(category: Category, message: String) -> Problem {
+        Problem { category, message }
+    }
+}
+
+pub fn exit_code(problems: &[Problem]) -> i32 {
+    problems.iter().fold(0, |code, p| code | p.category as i32)
+}
+
+/// Checks the whole config; `before_install` adds the checks that only hold
+/// on the live ISO before anything was touched, like disks not being mounted.
+pub fn validate(config: &Config, before_install: bool) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    check_stages(config, before_install, &mut problems);
+    check_devices(config, before_install, &mut problems);
+    check_swap(config, &mut problems);
+    check_identity(config, &mut problems);
+    check_system(config, &mut problems);
+    check_bootloader(config, before_install, &mut problems);
+    problems
+}
+
+/// Checks only the stages, for runs in the chroot or the installed system.
+pub fn validate_stages(config: &Config) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    check_stages(config, false, &mut problems);
+    problems
+}
+
+fn check_stages(config: &Config, before_install: bool, problems: &mut Vec<Problem>) {
+    let stages = &config.stages;
+    if !stages.map.contains_key(&stages.first_stage) {
+        problems.push(Problem::new(
+            Category::Stages,
+            format!("first stage '{}' does not exist", stages.first_stage),
+        ));
+    }
+
+    let mut names: Vec<&String> = stages.map.keys().collect();
+    names.sort();
+    for stage in &names {
+        for task in &stages.map[*stage] {
+            if !TASKS.contains_key(task) {
+                problems.push(Problem::new(
+                    Category::Stages,
+                    format!("there is no task {} in stage {}", task, stage),
+                ));
+            }
+        }
+    }
+
+    for problem in stages.check_dependencies() {
+        problems.push(Problem::new(Category::Stages, problem));
+    }
+
+    if before_install {
+        let chain = stages.chain();
+        for stage in names {
+            if !chain.contains(&stage.as_str()) {
+                problems.push(Problem::new(
+                    Category::Stages,
+                    format!("stage {} is never reached from {}", stage, stages.first_stage),
+                ));
+            }
+        }
+    }
+}
+
+fn check_devices(config: &Config, before_install: bool, problems: &mut Vec<Problem>) {
+    let installer = &config.installer;
+    let partitioned = !installer.partitions.is_empty();
+    let mut devices = vec![("install disk", &installer.install_disk)];
+    if installer.uses_system_disk() {
+        devices.push(("system disk", &installer.system_disk));
+    }
+    devices.push(("boot disk", &installer.boot_disk));
+
+    if partitioned {
+        check_partitions(config, problems);
+    }
+    match installer.system_filesystem() {
+        Filesystem::Fat32 => problems.push(Problem::new(
+            Category::Devices,
+            "the system can't be installed on fat32".to_string(),
+        )),
+        Filesystem::Btrfs => check_subvolumes(config, problems),
+        Filesystem::Ext4 | Filesystem::Xfs => {}
+    }
+    if let Some(size) = &installer.expected_size {
+        if safety::parse_size(size).is_none() {
+            problems.push(Problem::new(
+                Category::Devices,
+                format!("can't understand expected_size {}", size),
+            ));
+        }
+    }
+    if let Some(encryption) = &installer.encryption {
+        let name = &encryption.name;
+        if name.is_empty() || name.contains(|c: char| c == '/' || c.is_whitespace()) {
+            problems.push(Problem::new(
+                Category::Devices,
+                format!("'{}' can't be used as name of the encrypted container", name),
+            ));
+        }
+    }
+
+    if let Some(lvm) = &installer.lvm {
+        check_lvm(config, problems);
+        if !partitioned {
+            for device in &lvm.physical_volumes {
+                if !device.exists() {
+                    problems.push(Problem::new(
+                        Category::Devices,
+                        format!("physical volume {} doesn't exist", device.display()),
+                    ));
+                }
+            }
+        }
+    }
+
+    let mut mount_points = Vec::new();
+    for mount in disk::mounts(installer) {
+        if mount_points.contains(&mount.mount_point) {
+            problems.push(Problem::new(
+                Category::Devices,
+                format!("more than one filesystem is mounted at {}", mount.mount_point),
+            ));
+        }
+        mount_points.push(mount.mount_point);
+    }
+
+    let mut all_exist = true;
+    for (what, device) in devices.iter() {
+        if device.as_os_str().is_empty() {
+            all_exist = false;
+            problems.push(Problem::new(
+                Category::Devices,
+                format!("{} is not set and there are no partitions to take it from", what),
+            ));
+        } else if device.to_string_lossy().contains('=') {
+            // specs like UUID=... are resolved when the config is read, but
+            // there is nothing to look them up with in dry-run mode
+            if !dry_run() {
+                all_exist = false;
+                problems.push(Problem::new(
+                    Category::Devices,
+                    format!("{} {} doesn't match any device", what, device.display()),
+                ));
+            }
+        } else if partitioned && *what != "install disk" {
+            // created by `prepare`
+        } else if !device.exists() || device.is_dir() {
+            all_exist = false;
+            problems.push(Problem::new(
+                Category::Devices,
+                format!("{} {} doesn't exist", what, device.display()),
+            ));
+        }
+    }
+    if !all_exist || !before_install {
+        return;
+    }
+
+    let mounted = disk::mounted_devices();
+    for (what, device) in devices.iter() {
+        let name = disk::block_name(device);
+        if let Some(m) = mounted.iter().find(|m| {
+            let m = disk::block_name(m);
+            m == name || disk::is_partition_of(&m, &name)
+        }) {
+            problems.push(Problem::new(
+                Category::Devices,
+                format!("{} {} is in use ({} is mounted)", what, device.display(), m.display()),
+            ));
+        }
+    }
+
+    if partitioned {
+        return;
+    }
+    let disk = disk::block_name(&installer.install_disk);
+    for (what, device) in devices[1..].iter() {
+        if !disk::is_partition_of(&disk::block_name(device), &disk) {
+            problems.push(Problem::new(
+                Category::Devices,
+                format!(
+                    "{} {} is not a partition of install disk {}",
+                    what,
+                    device.display(),
+                    installer.install_disk.display()
+                ),
+            ));
+        }
+    }
+}
+
+/// GPT partition type grub keeps its core image in when booting with BIOS.
+const BIOS_BOOT: &str = "21686148-6449-6E6F-744E-656564454649";
+
+/// Kernel params that find and unlock the root filesystem or the
+/// hibernation image; `rootflags` are combined instead.
+const INSTALLER_PARAMS: [&str; 7] = [
+    "root",
+    "rw",
+    "ro",
+    "cryptdevice",
+    "rd.luks.name",
+    "resume",
+    "resume_offset",
+];
+
+fn check_bootloader(config: &Config, before_install: bool, problems: &mut Vec<Problem>) {
+    let installer = &config.installer;
+    let bootloader = &config.bootloader;
+    if bootloader.uki && bootloader.kind != BootloaderKind::SystemdBoot {
+        problems.push(Problem::new(
+            Category::Boot,
+            "unified kernel images are only set up for systemd-boot".to_string(),
+        ));
+    }
+    if bootloader.secure_boot && !bootloader.uki {
+        problems.push(Problem::new(
+            Category::Boot,
+            "secure_boot needs uki, otherwise initramfs and kernel options aren't signed"
+                .to_string(),
+        ));
+    }
+    if bootloader.kernels.is_empty() {
+        problems.push(Problem::new(
+            Category::Boot,
+            "no kernel is installed".to_string(),
+        ));
+    }
+    let mut names = Vec::new();
+    for kernel in &bootloader.kernels {
+        if names.contains(&&kernel.name) {
+            problems.push(Problem::new(
+                Category::Boot,
+                format!("kernel {} is listed more than once", kernel.name),
+            ));
+        }
+        names.push(&kernel.name);
+        if !KERNELS.contains(&kernel.name.as_str()) {
+            problems.push(Problem::new(
+                Category::Boot,
+                format!(
+                    "unknown kernel {}, use one of {}",
+                    kernel.name,
+                    KERNELS.join(", ")
+                ),
+            ));
+        }
+        if !kernel.params.is_empty() && bootloader.kind == BootloaderKind::Grub {
+            problems.push(Problem::new(
+                Category::Boot,
+                format!("grub can't boot {} with its own params", kernel.name),
+            ));
+        }
+    }
+    let params = bootloader
+        .kernel_params
+        .iter()
+        .chain(bootloader.kernels.iter().flat_map(|k| &k.params));
+    for param in params {
+        if INSTALLER_PARAMS.contains(&param_name(param)) {
+            problems.push(Problem::new(
+                Category::Boot,
+                format!("kernel param {} is set by the installer", param),
+            ));
+        }
+    }
+    let console_mode = bootloader.console_mode.as_str();
+    if !["keep", "auto", "max"].contains(&console_mode) && console_mode.parse::<u32>().is_err() {
+        problems.push(Problem::new(
+            Category::Boot,
+            format!("unknown console mode {}", console_mode),
+        ));
+    }
+    if let Some(default) = &bootloader.default_kernel {
+        if !names.contains(&default) {
+            problems.push(Problem::new(
+                Category::Boot,
+                format!("default kernel {} is not in kernels", default),
+            ));
+        }
+    }
+    let bios = match bootloader.firmware {
+        Firmware::Bios => true,
+        Firmware::Uefi => false,
+        // only the machine that is installed can tell
+        Firmware::Auto => before_install && !path_exists("/sys/firmware/efi"),
+    };
+    if !bios {
+        return;
+    }
+    match bootloader.kind {
+        BootloaderKind::SystemdBoot => problems.push(Problem::new(
+            Category::Boot,
+            "systemd-boot can't boot with BIOS, use grub".to_string(),
+        )),
+        BootloaderKind::Grub => {
+            let bios_boot = installer.partitions.iter().any(|p| {
+                p.type_guid
+                    .as_deref()
+                    .is_some_and(|t| t.eq_ignore_ascii_case(BIOS_BOOT))
+            });
+            if !installer.partitions.is_empty()
+                && installer.partition_table == PartitionTable::Gpt
+                && !bios_boot
+            {
+                problems.push(Problem::new(
+                    Category::Boot,
+                    format!(
+                        "grub needs a BIOS boot partition (type {}) on gpt disks",
+                        BIOS_BOOT
+                    ),
+                ));
+            }
+        }
+    }
+}
+
+fn check_partitions(config: &Config, problems: &mut Vec<Problem>) {
+    let partitions = &config.installer.partitions;
+    let mut mount_points = Vec::new();
+    for (i, partition) in partitions.iter().enumerate() {
+        if partition.size.is_none() && i + 1 != partitions.len() {
+            problems.push(Problem::new(
+                Category::Devices,
+                format!("only the last partition may take the rest of the disk, not #{}", i + 1),
+            ));
+        }
+        if let Some(label) = &partition.label {
+            // sfdisk reads it between quotes, gpt has room for 36 UTF-16 units
+            if label.contains(|c: char| c == '"' || c.is_control())
+                || label.encode_utf16().count() > 36
+            {
+                problems.push(Problem::new(
+                    Category::Devices,
+                    format!(
+                        "partition label {:?} has quotes, control characters or is too long",
+                        label
+                    ),
+                ));
+            }
+        }
+        if let Some(mount_point) = &partition.mount_point {
+            if !mount_point.starts_with('/') {
+                problems.push(Problem::new(
+                    Category::Devices,
+                    format!("mount point {} has to be absolute", mount_point),
+                ));
+            }
+            mount_points.push(mount_point);
+        }
+    }
+    if !mount_points.iter().any(|m| *m == "/boot") {
+        problems.push(Problem::new(
+            Category::Devices,
+            "no partition is mounted at /boot".to_string(),
+        ));
+    }
+    if !mount_points.iter().any(|m| *m == "/") {
+        match &config.installer.lvm {
+            None => problems.push(Problem::new(
+                Category::Devices,
+                "no partition is mounted at /".to_string(),
+            )),
+            Some(lvm) if lvm.physical_volumes.is_empty() => problems.push(Problem::new(
+                Category::Devices,
+                "no partition is mounted at /, LVM uses it as the physical volume".to_string(),
+            )),
+            Some(_) => {}
+        }
+    }
+}
+
+fn check_lvm(config: &Config, problems: &mut Vec<Problem>) {
+    let installer = &config.installer;
+    let lvm = match &installer.lvm {
+        Some(lvm) => lvm,
+        None => return,
+    };
+    if !valid_lvm_name(&lvm.volume_group) {
+        problems.push(Problem::new(
+            Category::Devices,
+            format!("invalid volume group name '{}'", lvm.volume_group),
+        ));
+    }
+    if installer.encryption.is_some() && !lvm.physical_volumes.is_empty() {
+        problems.push(Problem::new(
+            Category::Devices,
+            "with encryption the system disk is the only physical volume, \
+             physical_volumes can't be given"
+                .to_string(),
+        ));
+    }
+    if lvm.root().is_none() {
+        problems.push(Problem::new(
+            Category::Devices,
+            "no logical volume is mounted at /".to_string(),
+        ));
+    }
+    for volume in &lvm.volumes {
+        if !valid_lvm_name(&volume.name) {
+            problems.push(Problem::new(
+                Category::Devices,
+                format!("invalid logical volume name '{}'", volume.name),
+            ));
+        }
+        if let Some(mount_point) = &volume.mount_point {
+            if !mount_point.starts_with('/') {
+                problems.push(Problem::new(
+                    Category::Devices,
+                    format!("mount point {} has to be absolute", mount_point),
+                ));
+            }
+        }
+    }
+}
+
+/// Name as accepted by LVM for volume groups and logical volumes.
+fn valid_lvm_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('-')
+        && name != "."
+        && name != ".."
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "+_.-".contains(c))
+}
+
+fn check_subvolumes(config: &Config, problems: &mut Vec<Problem>) {
+    let btrfs = &config.installer.btrfs;
+    if btrfs.root().is_none() {
+        problems.push(Problem::new(
+            Category::Devices,
+            "no btrfs subvolume is mounted at /".to_string(),
+        ));
+    }
+    for subvolume in &btrfs.subvolumes {
+        if !subvolume.mount_point.starts_with('/') {
+            problems.push(Problem::new(
+                Category::Devices,
+                format!("mount point {} has to be absolute", subvolume.mount_point),
+            ));
+        }
+    }
+}
+
+fn check_swap(config: &Config, problems: &mut Vec<Problem>) {
+    let installer = &config.installer;
+    match &config.swap {
+        Some(Swap::Partition { device, .. }) => {
+            // partitions and logical volumes are created by `prepare`
+            let created = !installer.partitions.is_empty() || installer.lvm.is_some();
+            if !created && !device.exists() {
+                problems.push(Problem::new(
+                    Category::Devices,
+                    format!("swap partition {} doesn't exist", device.display()),
+                ));
+            }
+        }
+        Some(Swap::File { size, path, .. }) => {
+            if size.is_empty() {
+                problems.push(Problem::new(
+                    Category::Devices,
+                    "size of the swap file is not set".to_string(),
+                ));
+            }
+            if !path.is_absolute() {
+                problems.push(Problem::new(
+                    Category::Devices,
+                    format!("swap file {} has to be absolute", path.display()),
+                ));
+            }
+            let btrfs = &installer.btrfs;
+            if installer.system_filesystem() == Filesystem::Btrfs
+                && btrfs.root().is_some()
+                && btrfs.holding(path) == btrfs.root()
+            {
+                problems.push(Problem::new(
+                    Category::Devices,
+                    format!(
+                        "swap file {} is in the subvolume of /, which then can't be snapshotted",
+                        path.display()
+                    ),
+                ));
+            }
+        }
+        Some(Swap::Zram { .. }) | None => {}
+    }
+}
+
+fn check_identity(config: &Config, problems: &mut Vec<Problem>) {
+    let user = &config.user;
+    if !valid_hostname(&user.hostname) {
+        problems.push(Problem::new(
+            Category::Identity,
+            format!("invalid hostname '{}'", user.hostname),
+        ));
+    }
+    if !valid_username(&user.name) {
+        problems.push(Problem::new(
+            Category::Identity,
+            format!("invalid user name '{}'", user.name),
+        ));
+    }
+}
+
+fn check_system(config: &Config, problems: &mut Vec<Problem>) {
+    let system = &config.system;
+    let zoneinfo = Path::new("/usr/share/zoneinfo");
+    let timezone = Path::new(&system.timezone);
+    // without a tz database there is nothing to check against
+    if zoneinfo.is_dir()
+        && (!timezone.is_relative()
+            || timezone.components().any(|c| c.as_os_str() == "..")
+            || !zoneinfo.join(timezone).is_file())
+    {
+        problems.push(Problem::new(
+            Category::System,
+            format!("unknown timezone '{}'", system.timezone),
+        ));
+    }
+
+    if system.locales.is_empty() {
+        problems.push(Problem::new(
+            Category::System,
+            "at least one locale has to be generated".to_string(),
+        ));
+    }
+    let supported = read_to_string("/usr/share/i18n/SUPPORTED").unwrap_or_default();
+    for locale in &system.locales {
+        if !supported.is_empty() && !supported.lines().any(|l| l.trim() == locale.trim()) {
+            problems.push(Problem::new(
+                Category::System,
+                format!("unknown locale '{}'", locale),
+            ));
+        }
+    }
+    let lang = system.lang();
+    if !system
+        .locales
+        .iter()
+        .any(|l| l.split_whitespace().next() == Some(lang))
+    {
+        problems.push(Problem::new(
+            Category::System,
+            format!("LANG {} is not one of the generated locales", lang),
+        ));
+    }
+}
+
+/// RFC 1123 host name.
+pub fn valid_hostname(hostname: &str) -> bool {
+    !hostname.is_empty()
+        && hostname.len() <= 253
+        && hostname.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+/// User name as accepted by `useradd` with the default NAME_REGEX.
+pub fn valid_username(name: &str) -> bool {
+    let mut chars = name.chars();
+    name.len() <= 32
+        && chars
+            .next()
+            .is_some_and(|c| c.is_ascii_lowercase() || c == '_')
+        && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-')
+}